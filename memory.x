@@ -1,6 +1,7 @@
 MEMORY {
     BOOT2 : org = 0x10000000, len = 0x00000100 /* 0x10000000 to 0x10000100 */
-    FLASH : org = 0x10000100, len = 0x001FFF00 /* 0x10000100 to 0x10200000 */
+    /* The firmware slot. The rest of the flash holds the update slot and its state, see update.rs. */
+    FLASH : org = 0x10000100, len = 0x000FEF00 /* 0x10000100 to 0x100FF000 */
     STACK : org = 0x20000000, len = 0x00004000 /* 0x20000000 to 0x00004000 */
     RAM   : org = 0x20004000, len = 0x0003E000 /* 0x20004000 to 0x20042000 */
 }
@@ -15,7 +16,3 @@ SECTIONS {
 
 /* The stack is placed in the stack area. */
 _stack_start = ORIGIN(STACK) + LENGTH(STACK);
-
-/* The heap is placed in the region normally shared by the heap and stack. */
-_heap_start = __sheap;
-_heap_end = ORIGIN(RAM) + LENGTH(RAM);
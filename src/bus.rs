@@ -0,0 +1,317 @@
+//! The bus of the keyboard modules, like a numpad or a thumb cluster.
+//!
+//! The master talks to every module at once, while the modules share the line back and only
+//! send when polled. A module announces the size of its matrix when first polled, and is then
+//! polled in turn with the other known modules. Unknown ids are polled now and then to find new
+//! modules.
+
+use crate::{
+    comms::{ComLink, Update},
+    encoding::{encoded_len, is_pressed},
+    halves::Grid,
+    protocol::{Frame, FrameKind, FrameParser, Hello, Peer, MAX_DEVICES},
+    transport::Transport,
+};
+
+/// The largest matrix a module can have.
+pub const MAX_MODULE_ROWS: usize = 8;
+pub const MAX_MODULE_COLS: usize = 8;
+/// The length of the encoded state of every module, which smaller modules pad with zeros.
+pub const MODULE_STATE_LEN: usize = encoded_len(MAX_MODULE_ROWS, MAX_MODULE_COLS);
+/// The baud rate of the bus, which the master reads from its main loop.
+pub const BUS_BAUD: u32 = 115_200;
+/// How long a module has to answer a poll before the next one is polled.
+const POLL_SLOT_US: u64 = 1_000;
+/// How often an unknown id is polled.
+const DISCOVERY_US: u64 = 50_000;
+
+/// A module that announced itself.
+pub struct Module {
+    link: ComLink<MODULE_STATE_LEN>,
+    pub rows: u8,
+    pub cols: u8,
+}
+
+/// The master's end of the bus.
+pub struct Bus {
+    hello: Hello,
+    parser: FrameParser,
+    /// The modules by id. Id 0 is the other half, which isn't on the bus.
+    modules: [Option<Module>; MAX_DEVICES],
+    /// The id polled last.
+    polled: u8,
+    /// The unknown id polled last.
+    discovered: u8,
+    t_last_poll: u64,
+    t_last_discovery: u64,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Bus {
+            hello: Hello::new(Peer::Master, 0, 0),
+            parser: FrameParser::new(),
+            modules: Default::default(),
+            polled: 0,
+            discovered: 0,
+            t_last_poll: 0,
+            t_last_discovery: 0,
+        }
+    }
+
+    pub fn module(&self, id: u8) -> Option<&Module> {
+        self.modules.get(id as usize)?.as_ref()
+    }
+
+    /// Polls the next module, and returns the next update from any of them along with its id.
+    /// Only one update is returned per call, so that none of them is missed.
+    pub fn poll(
+        &mut self,
+        link: &mut impl Transport,
+        now: u64,
+    ) -> Option<(u8, Update<MODULE_STATE_LEN>)> {
+        for id in 1..MAX_DEVICES {
+            let Some(module) = &mut self.modules[id] else {
+                continue;
+            };
+            if let Some(update) = module.link.service(link, now) {
+                if update == Update::Lost {
+                    self.modules[id] = None;
+                }
+                return Some((id as u8, update));
+            }
+        }
+
+        if now - self.t_last_poll >= POLL_SLOT_US {
+            self.poll_next(link, now);
+        }
+
+        while let Some(byte) = link.read() {
+            let Some(frame) = self.parser.push(byte) else {
+                continue;
+            };
+            let id = frame.device as usize;
+            if id == 0 {
+                continue;
+            }
+            if let Some(hello) = Hello::from_frame(&frame) {
+                let (rows, cols) = (hello.rows, hello.cols);
+                let known =
+                    matches!(&self.modules[id], Some(m) if (m.rows, m.cols) == (rows, cols));
+                let fits = rows as usize <= MAX_MODULE_ROWS && cols as usize <= MAX_MODULE_COLS;
+                if !known && fits && self.hello.accepts(&hello) {
+                    self.modules[id] = Some(Module {
+                        link: ComLink::for_device(id as u8, self.hello),
+                        rows,
+                        cols,
+                    });
+                }
+            }
+            if let Some(module) = &mut self.modules[id] {
+                if let Some(update) = module.link.handle(&frame, link, now) {
+                    return Some((id as u8, update));
+                }
+            }
+        }
+        None
+    }
+
+    /// Polls the next known module, or an unknown id when it's time to look for new modules.
+    fn poll_next(&mut self, link: &mut impl Transport, now: u64) {
+        let unknown = if now - self.t_last_discovery >= DISCOVERY_US {
+            self.next_id(self.discovered, false)
+        } else {
+            None
+        };
+        let id = if let Some(id) = unknown {
+            self.discovered = id;
+            self.t_last_discovery = now;
+            id
+        } else if let Some(id) = self.next_id(self.polled, true) {
+            self.polled = id;
+            id
+        } else {
+            return;
+        };
+        if let Some(poll) = Frame::new(FrameKind::Poll, 0, &[]) {
+            link.write(&poll.for_device(id).encode());
+        }
+        self.t_last_poll = now;
+    }
+
+    /// The first id after `id`, in turn, of a module that is `known` or not.
+    fn next_id(&self, id: u8, known: bool) -> Option<u8> {
+        let ids = MAX_DEVICES as u8 - 1;
+        (1..=ids)
+            .map(|i| (id + i - 1) % ids + 1)
+            .find(|&id| self.modules[id as usize].is_some() == known)
+    }
+}
+
+/// Where the keys of a module go in the keymap.
+pub struct Placement {
+    /// The keymap position of the first key of the module.
+    pub row: usize,
+    pub col: usize,
+    /// The size of the area of the keymap given to the module. Keys outside of it are ignored.
+    pub rows: usize,
+    pub cols: usize,
+}
+
+impl Placement {
+    /// Applies an update of a module with `rows` rows to the keymap grid.
+    pub fn apply(&self, update: &Update<MODULE_STATE_LEN>, rows: usize, grid: &mut Grid) {
+        let mut set = |ri: usize, ci: usize, pressed: bool| {
+            if ri < self.rows && ci < self.cols {
+                if let Some(key) = grid
+                    .get_mut(self.row + ri)
+                    .and_then(|row| row.get_mut(self.col + ci))
+                {
+                    *key = pressed;
+                }
+            }
+        };
+        match update {
+            Update::Event(event) => set(event.row as usize, event.col as usize, event.pressed),
+            Update::State(state) => {
+                for ri in 0..self.rows {
+                    for ci in 0..self.cols {
+                        set(ri, ci, ri < rows && is_pressed(state, rows, ri, ci));
+                    }
+                }
+            }
+            // The keys held on the module can't be released anymore.
+            Update::Lost => {
+                for ri in 0..self.rows {
+                    for ci in 0..self.cols {
+                        set(ri, ci, false);
+                    }
+                }
+            }
+            // Modules aren't updated through the bus.
+            Update::Firmware(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{comms::SlaveLink, encoding::encode, layout, mock::Loopback, protocol::Event};
+
+    struct Modules {
+        bus: Bus,
+        master_end: Loopback,
+        modules: [(SlaveLink, Loopback, [u8; MODULE_STATE_LEN]); 2],
+        now: u64,
+    }
+
+    impl Modules {
+        /// A numpad with id 3 and a thumb cluster with id 9.
+        fn new() -> Self {
+            let (master_end, [a, b]) = Loopback::bus();
+            let mut numpad = [0; MODULE_STATE_LEN];
+            let mut pressed = [[false; 4]; 5];
+            pressed[4][0] = true;
+            encode(&pressed, &mut numpad).unwrap();
+            Modules {
+                bus: Bus::new(),
+                master_end,
+                modules: [
+                    (
+                        SlaveLink::on_bus(3, Hello::new(Peer::Module, 5, 4)),
+                        a,
+                        numpad,
+                    ),
+                    (
+                        SlaveLink::on_bus(9, Hello::new(Peer::Module, 2, 3)),
+                        b,
+                        [0; MODULE_STATE_LEN],
+                    ),
+                ],
+                now: 0,
+            }
+        }
+
+        fn run(&mut self, us: u64) -> std::vec::Vec<(u8, Update<MODULE_STATE_LEN>)> {
+            let mut updates = std::vec::Vec::new();
+            for _ in 0..us / 100 {
+                self.now += 100;
+                for (link, end, state) in &mut self.modules {
+                    link.update_link_state(self.now);
+                    link.poll(end, self.now, state);
+                }
+                while let Some(update) = self.bus.poll(&mut self.master_end, self.now) {
+                    updates.push(update);
+                }
+            }
+            updates
+        }
+    }
+
+    #[test]
+    fn modules_are_found_and_followed() {
+        let mut modules = Modules::new();
+        let updates = modules.run(2 * DISCOVERY_US * MAX_DEVICES as u64);
+        assert_eq!(
+            modules.bus.module(3).map(|m| (m.rows, m.cols)),
+            Some((5, 4))
+        );
+        assert_eq!(
+            modules.bus.module(9).map(|m| (m.rows, m.cols)),
+            Some((2, 3))
+        );
+        assert!(updates.contains(&(3, Update::State(modules.modules[0].2))));
+
+        let event = Event {
+            row: 1,
+            col: 2,
+            pressed: true,
+            time_us: 0,
+        };
+        modules.modules[1].0.push_event(event);
+        let updates = modules.run(10 * POLL_SLOT_US);
+        assert_eq!(updates, [(9, Update::Event(event))]);
+    }
+
+    #[test]
+    fn lost_modules_are_forgotten() {
+        let mut modules = Modules::new();
+        modules.run(2 * DISCOVERY_US * MAX_DEVICES as u64);
+        let mut updates = std::vec::Vec::new();
+        for _ in 0..2_000 {
+            modules.now += 1_000;
+            updates.extend(modules.bus.poll(&mut modules.master_end, modules.now));
+        }
+        assert!(updates.contains(&(3, Update::Lost)));
+        assert!(updates.contains(&(9, Update::Lost)));
+        assert!(modules.bus.module(3).is_none());
+    }
+
+    #[test]
+    fn placement_maps_module_keys() {
+        let placement = Placement {
+            row: 2,
+            col: 4,
+            rows: 2,
+            cols: 3,
+        };
+        let mut grid = [[false; layout::COLS]; layout::ROWS];
+        let mut state = [0; MODULE_STATE_LEN];
+        let mut pressed = [[false; 3]; 2];
+        pressed[1][2] = true;
+        encode(&pressed, &mut state).unwrap();
+        placement.apply(&Update::State(state), 2, &mut grid);
+        assert!(grid[3][6]);
+
+        let event = Event {
+            row: 0,
+            col: 5,
+            pressed: true,
+            time_us: 0,
+        };
+        placement.apply(&Update::Event(event), 2, &mut grid);
+        placement.apply(&Update::Lost, 2, &mut grid);
+        assert_eq!(grid, [[false; layout::COLS]; layout::ROWS]);
+    }
+}
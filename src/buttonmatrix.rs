@@ -3,6 +3,58 @@ use embedded_hal::{
     digital::v2::{InputPin, OutputPin},
 };
 
+/// The pin that failed during a scan.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScanError {
+    /// Driving the row failed, or it never read back at the level it was driven to.
+    Row(usize),
+    /// Reading the column failed while scanning the row.
+    Col { row: usize, col: usize },
+    /// The PIO scanner didn't finish a snapshot in time.
+    #[allow(unused)]
+    Stalled,
+}
+
+/// Reports which keys in a grid of `ROWS` x `COLS` are pressed.
+pub trait Scanner<const COLS: usize, const ROWS: usize> {
+    fn scan(&mut self, delay: &mut impl DelayUs<u32>) -> Result<[[bool; COLS]; ROWS], ScanError>;
+}
+
+/// Which way the diodes of a matrix conduct, which decides how rows are driven and columns read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(unused)]
+pub enum DiodeDirection {
+    /// Rows are driven high and the columns are read with pull-downs.
+    Row2Col,
+    /// Rows are driven low and the columns are read with pull-ups, so pressed keys read low.
+    Col2Row,
+}
+
+impl DiodeDirection {
+    /// Sets row `ri` to its active or idle level.
+    fn drive<P: OutputPin>(self, pin: &mut P, active: bool, ri: usize) -> Result<(), ScanError> {
+        let result = if active == (self == DiodeDirection::Row2Col) {
+            pin.set_high()
+        } else {
+            pin.set_low()
+        };
+        result.map_err(|_| ScanError::Row(ri))
+    }
+
+    /// Whether column `ci` shows that the key on the driven row `ri` is pressed.
+    fn read<P: InputPin>(self, pin: &P, ri: usize, ci: usize) -> Result<bool, ScanError> {
+        let level = pin
+            .is_high()
+            .map_err(|_| ScanError::Col { row: ri, col: ci })?;
+        Ok(self.is_pressed(level))
+    }
+
+    /// Whether a column read at `level` belongs to a pressed key.
+    pub fn is_pressed(self, level: bool) -> bool {
+        level == (self == DiodeDirection::Row2Col)
+    }
+}
+
 pub struct ButtonMatrix<OUTPIN, INPPIN, const COLS: usize, const ROWS: usize>
 where
     OUTPIN: OutputPin,
@@ -10,6 +62,7 @@ where
 {
     pub rows: [OUTPIN; ROWS],
     pub cols: [INPPIN; COLS],
+    pub diodes: DiodeDirection,
 }
 
 pub struct PressedIterator<'a, 'b, D, OUTPIN, INPPIN, const COLS: usize, const ROWS: usize>
@@ -23,58 +76,309 @@ where
     cur_col: usize,
 }
 
+impl<'a, 'b, D: DelayUs<u16>, OUTPIN, INPPIN, const COLS: usize, const ROWS: usize>
+    PressedIterator<'a, 'b, D, OUTPIN, INPPIN, COLS, ROWS>
+where
+    OUTPIN: OutputPin,
+    INPPIN: InputPin,
+{
+    fn check(&mut self, ri: usize, ci: usize) -> Result<bool, ScanError> {
+        let row_pin = &mut self.butmat.rows[ri];
+        let col_pin = &self.butmat.cols[ci];
+        let diodes = self.butmat.diodes;
+
+        diodes.drive(row_pin, true, ri)?;
+        self.delay.delay_us(1);
+        let pressed = diodes.read(col_pin, ri, ci);
+        diodes.drive(row_pin, false, ri)?;
+        pressed
+    }
+}
+
 impl<'a, 'b, D: DelayUs<u16>, OUTPIN, INPPIN, const COLS: usize, const ROWS: usize> Iterator
     for PressedIterator<'a, 'b, D, OUTPIN, INPPIN, COLS, ROWS>
 where
     OUTPIN: OutputPin,
     INPPIN: InputPin,
 {
-    type Item = (usize, usize);
+    type Item = Result<(usize, usize), ScanError>;
 
+    /// Returns the next pressed key. The iteration ends after the first error.
     fn next(&mut self) -> Option<Self::Item> {
-        if self.cur_col >= COLS {
-            self.cur_col = 0;
-            self.cur_row += 1;
+        while self.cur_row < ROWS {
+            let (ri, ci) = (self.cur_row, self.cur_col);
+            self.cur_col += 1;
+            if self.cur_col >= COLS {
+                self.cur_col = 0;
+                self.cur_row += 1;
+            }
+
+            match self.check(ri, ci) {
+                Ok(false) => {}
+                Ok(true) => return Some(Ok((ri, ci))),
+                Err(err) => {
+                    self.cur_row = ROWS;
+                    return Some(Err(err));
+                }
+            }
         }
-        if self.cur_row >= ROWS {
-            return None;
-        };
+        None
+    }
+}
 
-        let row_pin = &mut self.butmat.rows[self.cur_row];
-        let col_pin = &mut self.butmat.cols[self.cur_col];
+impl<OUTPIN, INPPIN, const COLS: usize, const ROWS: usize> ButtonMatrix<OUTPIN, INPPIN, COLS, ROWS>
+where
+    OUTPIN: OutputPin,
+    INPPIN: InputPin,
+{
+    /// Creates the matrix and sets every row to its idle level.
+    #[allow(unused)]
+    pub fn new(
+        mut rows: [OUTPIN; ROWS],
+        cols: [INPPIN; COLS],
+        diodes: DiodeDirection,
+    ) -> Result<Self, ScanError> {
+        for (ri, row_pin) in rows.iter_mut().enumerate() {
+            diodes.drive(row_pin, false, ri)?;
+        }
+        Ok(ButtonMatrix { rows, cols, diodes })
+    }
 
-        row_pin.set_high().ok()?;
-        self.delay.delay_us(1);
-        if col_pin.is_high().ok()? {
-            row_pin.set_low().ok()?;
-            Some((self.cur_row, self.cur_col))
-        } else {
-            row_pin.set_low().ok()?;
-            None
+    /// Iterates over the pressed keys, checking one key at a time.
+    #[allow(unused)]
+    pub fn pressed<'a, 'b, D: DelayUs<u16>>(
+        &'a mut self,
+        delay: &'b mut D,
+    ) -> PressedIterator<'a, 'b, D, OUTPIN, INPPIN, COLS, ROWS> {
+        PressedIterator {
+            butmat: self,
+            delay,
+            cur_row: 0,
+            cur_col: 0,
         }
     }
 }
 
-impl<OUTPIN, INPPIN, const COLS: usize, const ROWS: usize> ButtonMatrix<OUTPIN, INPPIN, COLS, ROWS>
+impl<OUTPIN, INPPIN, const COLS: usize, const ROWS: usize> Scanner<COLS, ROWS>
+    for ButtonMatrix<OUTPIN, INPPIN, COLS, ROWS>
 where
     OUTPIN: OutputPin,
     INPPIN: InputPin,
 {
-    pub fn scan(&mut self, delay: &mut impl DelayUs<u32>) -> Option<[[bool; COLS]; ROWS]> {
+    fn scan(&mut self, delay: &mut impl DelayUs<u32>) -> Result<[[bool; COLS]; ROWS], ScanError> {
         let mut pressed = [[false; COLS]; ROWS];
 
         for (ri, row_pin) in self.rows.iter_mut().enumerate() {
             for (ci, col_pin) in self.cols.iter_mut().enumerate() {
-                row_pin.set_high().ok()?;
+                self.diodes.drive(row_pin, true, ri)?;
                 delay.delay_us(1);
 
-                if col_pin.is_high().ok()? {
-                    pressed[ri][ci] = true;
-                }
+                let key_pressed = self.diodes.read(col_pin, ri, ci);
+                self.diodes.drive(row_pin, false, ri)?;
+                pressed[ri][ci] = key_pressed?;
+            }
+        }
+        Ok(pressed)
+    }
+}
+
+/// The shortest wait after driving a row of a [`PortMatrix`], before its settle reads. Calibration
+/// only sees the driven row itself, not the columns discharging from the previous row through
+/// their pulls, which this leaves time for.
+const SETTLE_FLOOR_US: u32 = 1;
+/// Extra bank reads after a driven row of a [`PortMatrix`] reads back at its active level.
+#[cfg(any(not(feature = "pio-scan"), feature = "module"))]
+pub const SETTLE_MARGIN: u16 = 8;
+/// Bank reads used to settle rows that failed calibration.
+#[cfg(any(not(feature = "pio-scan"), feature = "module"))]
+pub const SETTLE_FALLBACK: u16 = 64;
+
+/// A GPIO bank whose input levels can be read all at once.
+pub trait InputBank {
+    fn read(&mut self) -> u32;
+}
 
-                row_pin.set_low().ok()?;
+/// A button matrix that reads every column at once from a single [`InputBank`] read, so only one
+/// settle delay per row is needed. The delay is at least [`SETTLE_FLOOR_US`], followed by the
+/// calibrated bank reads.
+pub struct PortMatrix<OUTPIN, BANK, const COLS: usize, const ROWS: usize>
+where
+    OUTPIN: OutputPin,
+    BANK: InputBank,
+{
+    pub rows: [OUTPIN; ROWS],
+    /// The bit of each column in the bank.
+    pub cols: [u8; COLS],
+    pub bank: BANK,
+    pub diodes: DiodeDirection,
+    /// How many bank reads to discard after driving each row and waiting [`SETTLE_FLOOR_US`],
+    /// before the columns are sampled.
+    pub settle_reads: [u16; ROWS],
+}
+
+impl<OUTPIN, BANK, const COLS: usize, const ROWS: usize> PortMatrix<OUTPIN, BANK, COLS, ROWS>
+where
+    OUTPIN: OutputPin,
+    BANK: InputBank,
+{
+    /// Sets every row to its idle level, then measures how many bank reads it takes for each
+    /// driven row to read back at its active level, given the bit of each row in the bank, and
+    /// sets the settle delays to that plus `margin` reads. This covers rows that are slow to
+    /// drive, while [`SETTLE_FLOOR_US`] covers the columns.
+    ///
+    /// Fails with [`ScanError::Row`] if a row never reads back at its active level.
+    #[allow(unused)]
+    pub fn calibrate(&mut self, row_bits: [u8; ROWS], margin: u16) -> Result<(), ScanError> {
+        const MAX_READS: u16 = 1000;
+
+        for (ri, row_pin) in self.rows.iter_mut().enumerate() {
+            self.diodes.drive(row_pin, false, ri)?;
+        }
+        for (ri, row_pin) in self.rows.iter_mut().enumerate() {
+            self.diodes.drive(row_pin, true, ri)?;
+            let reads = (1..=MAX_READS).find(|_| {
+                let level = self.bank.read() & (1 << row_bits[ri]) != 0;
+                // A driven row reads back like a column of a pressed key.
+                self.diodes.is_pressed(level)
+            });
+            self.diodes.drive(row_pin, false, ri)?;
+            self.settle_reads[ri] = reads.ok_or(ScanError::Row(ri))? + margin;
+        }
+        Ok(())
+    }
+}
+
+impl<OUTPIN, BANK, const COLS: usize, const ROWS: usize> Scanner<COLS, ROWS>
+    for PortMatrix<OUTPIN, BANK, COLS, ROWS>
+where
+    OUTPIN: OutputPin,
+    BANK: InputBank,
+{
+    fn scan(&mut self, delay: &mut impl DelayUs<u32>) -> Result<[[bool; COLS]; ROWS], ScanError> {
+        let mut pressed = [[false; COLS]; ROWS];
+
+        for (ri, row_pin) in self.rows.iter_mut().enumerate() {
+            self.diodes.drive(row_pin, true, ri)?;
+            delay.delay_us(SETTLE_FLOOR_US);
+            for _ in 0..self.settle_reads[ri] {
+                self.bank.read();
+            }
+            let levels = self.bank.read();
+            self.diodes.drive(row_pin, false, ri)?;
+
+            for (ci, &bit) in self.cols.iter().enumerate() {
+                pressed[ri][ci] = self.diodes.is_pressed(levels & (1 << bit) != 0);
             }
         }
-        Some(pressed)
+        Ok(pressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::mock::{CountingDelay, MockMatrix, NoDelay};
+
+    fn button_matrix(
+        mock: &MockMatrix,
+        diodes: DiodeDirection,
+    ) -> ButtonMatrix<crate::mock::MockRow, crate::mock::MockCol, 3, 2> {
+        ButtonMatrix::new(mock.rows(), mock.cols(), diodes).unwrap()
+    }
+
+    #[test]
+    fn scan_reports_pressed_keys() {
+        for diodes in [DiodeDirection::Row2Col, DiodeDirection::Col2Row] {
+            let mock = MockMatrix::new(3, 2, diodes);
+            let mut butmat = button_matrix(&mock, diodes);
+            assert_eq!(butmat.scan(&mut NoDelay), Ok([[false; 3]; 2]));
+
+            mock.set(0, 2, true);
+            mock.set(1, 0, true);
+            mock.set(1, 1, true);
+            assert_eq!(
+                butmat.scan(&mut NoDelay),
+                Ok([[false, false, true], [true, true, false]])
+            );
+        }
+    }
+
+    #[test]
+    fn scan_leaves_rows_idle() {
+        let mock = MockMatrix::new(3, 2, DiodeDirection::Col2Row);
+        let mut butmat = button_matrix(&mock, DiodeDirection::Col2Row);
+        mock.set(1, 1, true);
+        butmat.scan(&mut NoDelay).unwrap();
+        assert!(mock.row_level(0) && mock.row_level(1));
+    }
+
+    #[test]
+    fn scan_fails_on_pin_errors() {
+        let mock = MockMatrix::new(3, 2, DiodeDirection::Row2Col);
+        let mut butmat = button_matrix(&mock, DiodeDirection::Row2Col);
+        mock.fail_col(1);
+        assert_eq!(
+            butmat.scan(&mut NoDelay),
+            Err(ScanError::Col { row: 0, col: 1 })
+        );
+        // The row is released even though the column failed.
+        assert!(!mock.row_level(0));
+
+        let mock = MockMatrix::new(3, 2, DiodeDirection::Row2Col);
+        let mut butmat = button_matrix(&mock, DiodeDirection::Row2Col);
+        mock.fail_row(1);
+        assert_eq!(butmat.scan(&mut NoDelay), Err(ScanError::Row(1)));
+    }
+
+    #[test]
+    fn pressed_iterator() {
+        let mock = MockMatrix::new(3, 2, DiodeDirection::Row2Col);
+        let mut butmat = button_matrix(&mock, DiodeDirection::Row2Col);
+        assert_eq!(butmat.pressed(&mut NoDelay).next(), None);
+
+        mock.set(0, 0, true);
+        mock.set(0, 2, true);
+        mock.set(1, 2, true);
+        let pressed: Vec<_> = butmat.pressed(&mut NoDelay).collect();
+        assert_eq!(pressed, [Ok((0, 0)), Ok((0, 2)), Ok((1, 2))]);
+    }
+
+    #[test]
+    fn pressed_iterator_stops_on_pin_errors() {
+        let mock = MockMatrix::new(3, 2, DiodeDirection::Row2Col);
+        let mut butmat = button_matrix(&mock, DiodeDirection::Row2Col);
+        mock.set(0, 0, true);
+        mock.set(1, 2, true);
+        mock.fail_row(1);
+        let pressed: Vec<_> = butmat.pressed(&mut NoDelay).collect();
+        assert_eq!(pressed, [Ok((0, 0)), Err(ScanError::Row(1))]);
+    }
+
+    #[test]
+    fn port_matrix() {
+        for diodes in [DiodeDirection::Row2Col, DiodeDirection::Col2Row] {
+            let mock = MockMatrix::new(3, 2, diodes);
+            let mut butmat = PortMatrix {
+                rows: mock.rows(),
+                cols: [0, 1, 2],
+                bank: mock.bank(),
+                diodes,
+                settle_reads: [0; 2],
+            };
+            assert_eq!(butmat.calibrate([3, 4], 2), Ok(()));
+            assert_eq!(butmat.settle_reads, [3, 3]);
+
+            mock.set(0, 1, true);
+            mock.set(1, 2, true);
+            let mut delay = CountingDelay { us: 0 };
+            assert_eq!(
+                butmat.scan(&mut delay),
+                Ok([[false, true, false], [false, false, true]])
+            );
+            // Every row waits at least the floor, however fast it read back.
+            assert_eq!(delay.us, 2 * SETTLE_FLOOR_US);
+        }
     }
 }
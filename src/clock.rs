@@ -0,0 +1,162 @@
+//! Estimation of the clock of the slave, so that the times of its events can be compared with the
+//! master's.
+//!
+//! The master periodically sends the time of its clock, and the slave sends it back along with the
+//! time of its own. Assuming that both ways take as long, the slave read its clock halfway through
+//! the round trip, which gives the offset between the clocks. Round trips much longer than the
+//! shortest recent one are ignored, since their halves are less likely to be equal. The drift of
+//! the crystals is estimated from how the offset changes over time.
+
+/// Round trips longer than the shortest recent one by more than this are ignored.
+const MAX_EXTRA_RTT_US: u64 = 500;
+/// How much longer the shortest round trip is assumed to get with every measurement, so that it
+/// follows a link that got slower.
+const MIN_RTT_DECAY_US: u64 = 20;
+/// How long the offset is followed before the drift is estimated from it.
+const DRIFT_SPAN_US: u64 = 10_000_000;
+/// The largest drift believed, in parts per billion. The crystals are within 30 ppm.
+const MAX_DRIFT_PPB: i64 = 200_000;
+
+pub struct ClockSync {
+    /// The offset of the master's clock from the slave's, and the master's time it was estimated
+    /// at.
+    offset: Option<(i64, u64)>,
+    /// How much faster the master's clock is, in parts per billion.
+    drift_ppb: i64,
+    /// The offset the drift is measured from, and when it was estimated.
+    anchor: Option<(i64, u64)>,
+    min_rtt: u64,
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        ClockSync {
+            offset: None,
+            drift_ppb: 0,
+            anchor: None,
+            min_rtt: u64::MAX,
+        }
+    }
+
+    /// Adds a measurement, where the master sent its time `t_sent`, the slave replied with the
+    /// time `t_remote` of its clock and the master got the reply at `t_received`.
+    pub fn sample(&mut self, t_sent: u64, t_remote: u64, t_received: u64) {
+        let Some(rtt) = t_received.checked_sub(t_sent) else {
+            return;
+        };
+        self.min_rtt = self.min_rtt.saturating_add(MIN_RTT_DECAY_US).min(rtt);
+        if rtt > self.min_rtt + MAX_EXTRA_RTT_US {
+            return;
+        }
+        let measured = (t_sent + rtt / 2) as i64 - t_remote as i64;
+        let offset = match self.offset_at(t_received) {
+            // Measurements are smoothed, as their round trips aren't quite symmetric.
+            Some(predicted) => predicted + (measured - predicted) / 2,
+            None => measured,
+        };
+        self.offset = Some((offset, t_received));
+
+        match self.anchor {
+            Some((anchor, t_anchor)) if t_received - t_anchor >= DRIFT_SPAN_US => {
+                let span = (t_received - t_anchor) as i64;
+                let drift =
+                    ((offset - anchor) * 1_000_000_000 / span).clamp(-MAX_DRIFT_PPB, MAX_DRIFT_PPB);
+                self.drift_ppb += (drift - self.drift_ppb) / 2;
+                self.anchor = Some((offset, t_received));
+            }
+            Some(_) => {}
+            None => self.anchor = Some((offset, t_received)),
+        }
+    }
+
+    /// The offset of the master's clock from the slave's at the master's time `now`.
+    fn offset_at(&self, now: u64) -> Option<i64> {
+        let (offset, t) = self.offset?;
+        Some(offset + self.drift_ppb * (now as i64 - t as i64) / 1_000_000_000)
+    }
+
+    /// Converts the time `remote_us` of the slave's clock, of which only the low 32 bits are
+    /// sent, to the master's clock. It must be from shortly before the master's time `now`, and
+    /// is returned as no later than `now`. Returns `None` until the clocks have been measured.
+    pub fn to_local(&self, remote_us: u32, now: u64) -> Option<u64> {
+        let offset = self.offset_at(now)?;
+        let remote_now = now as i64 - offset;
+        let age = (remote_now as u32).wrapping_sub(remote_us) as i32 as i64;
+        Some((now as i64 - age.max(0)).max(0) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A slave clock started at `start` on the master's clock, running `ppm` faster than it.
+    fn remote(start: u64, ppm: i64, master: u64) -> u64 {
+        let elapsed = (master - start) as i64;
+        (elapsed + elapsed * ppm / 1_000_000) as u64
+    }
+
+    #[test]
+    fn offset_follows_a_late_start() {
+        let mut sync = ClockSync::new();
+        assert_eq!(sync.to_local(0, 1_000), None);
+        for i in 1..10 {
+            let t = i * 250_000;
+            sync.sample(t, remote(100_000, 0, t + 300), t + 600);
+        }
+        let now = 3_000_000;
+        let event = remote(100_000, 0, now - 2_000) as u32;
+        let local = sync.to_local(event, now).unwrap();
+        assert!(local.abs_diff(now - 2_000) <= 10, "{}", local);
+    }
+
+    #[test]
+    fn slow_round_trips_are_ignored() {
+        let mut sync = ClockSync::new();
+        for i in 1..10 {
+            let t = i * 250_000;
+            sync.sample(t, remote(0, 0, t + 300), t + 600);
+        }
+        // The reply waited a long time before it was sent.
+        let t = 3_000_000;
+        sync.sample(t, remote(0, 0, t + 9_000), t + 9_300);
+        let local = sync.to_local(remote(0, 0, t) as u32, t + 9_300).unwrap();
+        assert!(local.abs_diff(t) <= 10, "{}", local);
+    }
+
+    #[test]
+    fn drift_is_followed() {
+        let mut sync = ClockSync::new();
+        let ppm = 40;
+        let mut t = 0;
+        while t < 60_000_000 {
+            t += 250_000;
+            sync.sample(t, remote(0, ppm, t + 300), t + 600);
+        }
+        assert!(
+            (sync.drift_ppb + ppm * 1_000).abs() < 5_000,
+            "{}",
+            sync.drift_ppb
+        );
+
+        // Without measurements for a while, the drift keeps the clocks together.
+        let now = t + 20_000_000;
+        let local = sync.to_local(remote(0, ppm, now) as u32, now).unwrap();
+        assert!(local.abs_diff(now) <= 100, "{}", local.abs_diff(now));
+    }
+
+    #[test]
+    fn timestamps_wrap_around() {
+        let mut sync = ClockSync::new();
+        let start = 1 << 33;
+        for i in 1..10 {
+            let t = start + i * 250_000;
+            sync.sample(t, t + 300 - 5_000, t + 600);
+        }
+        let now = start + 3_000_000;
+        let event = (now - 5_000 - 1_000) as u32;
+        assert_eq!(sync.to_local(event, now), Some(now - 1_000));
+        // Timestamps slightly ahead of the estimate aren't moved into the future.
+        assert_eq!(sync.to_local(event.wrapping_add(2_000), now), Some(now));
+    }
+}
@@ -1,58 +1,1019 @@
-use embedded_hal::{
-    serial::{Read, Write},
-    timer::CountDown,
+use core::fmt;
+
+use heapless::{Deque, Vec};
+
+use crate::{
+    clock::ClockSync,
+    protocol::{
+        Event, Frame, FrameKind, FrameParser, Greeting, Hello, Message, UpdateStatus,
+        HELLO_REQUEST, MAX_PAYLOAD,
+    },
+    transport::Transport,
 };
-use rp_pico::hal::uart::{Enabled, UartDevice, UartPeripheral, ValidUartPinout};
 
-pub struct ComLink<const N: usize, CD> {
-    buffer: [u8; N],
-    recvd: usize,
-    is_waiting: bool,
-    request_countdown: CD,
+/// Messages that can wait for the slave at the same time.
+pub const OUTBOX_CAPACITY: usize = 4;
+/// Events that can wait for an acknowledgement from the master at the same time.
+pub const EVENT_QUEUE_CAPACITY: usize = 32;
+/// How long the slave waits for an acknowledgement before sending its events again.
+const RETRANSMIT_US: u64 = 5_000;
+/// How long the master waits for an acknowledgement before sending a message again.
+const MESSAGE_RETRANSMIT_US: u64 = 10_000;
+/// How often the slave sends its full state.
+const STATE_US: u64 = 100_000;
+/// How long the link may be quiet before it's considered degraded. Both halves hear from each
+/// other at least every [`STATE_US`], when the slave sends its state and the master acknowledges.
+const DEGRADED_US: u64 = 250_000;
+/// How long the link may be quiet before it's considered lost.
+const LOST_US: u64 = 1_000_000;
+/// How often the master measures the clock of the slave.
+const SYNC_US: u64 = 250_000;
+
+/// What the master learns from the slave.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Update<const N: usize> {
+    Event(Event),
+    /// The full encoded state of the slave.
+    State([u8; N]),
+    /// The link was lost, so the state of the slave is unknown until it's sent again.
+    Lost,
+    /// How the slave took a firmware update.
+    Firmware(UpdateStatus),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkState {
+    Connected,
+    /// Nothing has been heard for a while.
+    Degraded,
+    Lost,
+}
+
+/// Tracks the state of the link from when the other half was last heard from.
+pub struct LinkMonitor {
+    t_last_heard: Option<u64>,
+    state: LinkState,
 }
 
-impl<const N: usize, CD> ComLink<N, CD>
-where
-    CD: CountDown,
-{
-    pub fn new(countdown: CD) -> Self {
+impl LinkMonitor {
+    pub fn new() -> Self {
+        LinkMonitor {
+            t_last_heard: None,
+            state: LinkState::Lost,
+        }
+    }
+
+    /// Records that a valid frame was received.
+    pub fn heard(&mut self, now: u64) {
+        self.t_last_heard = Some(now);
+    }
+
+    /// Updates the state for the time `now`, and returns it if it changed.
+    pub fn update(&mut self, now: u64) -> Option<LinkState> {
+        let state = match self.t_last_heard {
+            Some(t) if now - t < DEGRADED_US => LinkState::Connected,
+            Some(t) if now - t < LOST_US => LinkState::Degraded,
+            _ => LinkState::Lost,
+        };
+        if state == self.state {
+            return None;
+        }
+        self.state = state;
+        Some(state)
+    }
+
+    pub fn state(&self) -> LinkState {
+        self.state
+    }
+}
+
+/// Statistics of delays measured on the link, in microseconds.
+pub struct Latency {
+    count: u32,
+    total: u64,
+    last: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Latency {
+    pub fn new() -> Self {
+        Latency {
+            count: 0,
+            total: 0,
+            last: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+
+    pub fn record(&mut self, us: u64) {
+        self.count = self.count.saturating_add(1);
+        self.total = self.total.saturating_add(us);
+        self.last = us;
+        self.min = self.min.min(us);
+        self.max = self.max.max(us);
+    }
+}
+
+impl fmt::Display for Latency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.count == 0 {
+            return write!(f, "not measured");
+        }
+        write!(
+            f,
+            "last {} us, min {} us, mean {} us, max {} us",
+            self.last,
+            self.min,
+            self.total / self.count as u64,
+            self.max
+        )
+    }
+}
+
+/// The master's end of the split link. Receives the events of the slave and delivers messages
+/// to it.
+///
+/// Messages are sent one at a time and retransmitted until the slave has acknowledged them.
+/// Nothing is exchanged but hellos until the slave has sent one that the master accepts, except
+/// for updates, which reach a slave of any protocol version.
+pub struct ComLink<const N: usize> {
+    device: u8,
+    hello: Hello,
+    /// The last hello of the slave.
+    peer: Option<Greeting>,
+    parser: FrameParser,
+    monitor: LinkMonitor,
+    events: EventTracker,
+    outbox: Vec<Message, OUTBOX_CAPACITY>,
+    msg_seq: u8,
+    /// The sequence number the first message of the outbox was sent with, if it has been sent.
+    in_flight: Option<u8>,
+    t_last_message_sent: u64,
+    clock: ClockSync,
+    t_last_sync: u64,
+    /// The round trips of the clock measurements.
+    round_trip: Latency,
+}
+
+impl<const N: usize> ComLink<N> {
+    /// Creates the master's end of the link, which introduces itself with `hello`.
+    pub fn new(hello: Hello) -> Self {
+        Self::for_device(0, hello)
+    }
+
+    /// Creates the master's end of the link to `device`, for a module on the bus.
+    pub fn for_device(device: u8, hello: Hello) -> Self {
         ComLink {
-            buffer: [0; N],
-            recvd: 0,
-            is_waiting: false,
-            request_countdown: countdown,
+            device,
+            hello,
+            peer: None,
+            parser: FrameParser::new(),
+            monitor: LinkMonitor::new(),
+            events: EventTracker::new(),
+            outbox: Vec::new(),
+            msg_seq: 0,
+            in_flight: None,
+            t_last_message_sent: 0,
+            clock: ClockSync::new(),
+            t_last_sync: 0,
+            round_trip: Latency::new(),
+        }
+    }
+
+    /// Queues a message for the slave, replacing any queued message it supersedes. Returns
+    /// `false` if the outbox is full.
+    pub fn send(&mut self, message: Message) -> bool {
+        if let Some(index) = self.outbox.iter().position(|m| message.supersedes(m)) {
+            if index == 0 {
+                // The slave might already have the old message under this sequence number.
+                self.in_flight = None;
+            }
+            self.outbox[index] = message;
+            return true;
         }
+        self.outbox.push(message).is_ok()
     }
 
-    pub fn poll<D, P>(&mut self, uart: &mut UartPeripheral<Enabled, D, P>) -> Option<&[u8; N]>
-    where
-        D: UartDevice,
-        P: ValidUartPinout<D>,
-    {
-        if self.request_countdown.wait().is_ok() {
-            if self.is_waiting {
-                // Clear input of leftover input.
-                let mut trash_bin = [0; 8];
-                uart.read_raw(&mut trash_bin).ok()?;
-                self.recvd = 0;
+    fn send_next(&mut self, link: &mut impl Transport, now: u64) {
+        if self.in_flight.is_none() && !self.is_compatible() {
+            // Only updates get through to an incompatible slave, so they go first.
+            if let Some(index) = self.outbox.iter().position(is_update) {
+                self.outbox[..=index].rotate_right(1);
             }
-            uart.write(0).ok()?;
-            self.is_waiting = true;
         }
+        let Some(&message) = self.outbox.first().filter(|m| self.can_send(m)) else {
+            return;
+        };
+        let seq = *self.in_flight.get_or_insert_with(|| {
+            self.msg_seq = self.msg_seq.wrapping_add(1);
+            self.msg_seq
+        });
+        link.write(&message.to_frame(seq).for_device(self.device).encode());
+        self.t_last_message_sent = now;
+    }
 
-        if self.is_waiting {
-            if uart.uart_is_readable() {
-                let byte = uart.read().ok()?;
-                self.buffer[self.recvd] = byte;
-                self.recvd += 1;
+    fn ack_events(&self, link: &mut impl Transport) {
+        let Some(last) = self.events.last() else {
+            return;
+        };
+        if let Some(ack) = Frame::new(FrameKind::Ack, last, &[]) {
+            link.write(&ack.for_device(self.device).encode());
+        }
+    }
+
+    /// Sends the pending message, and returns the next update from the slave. Only one update is
+    /// returned per call, so that none of them is missed.
+    ///
+    /// Once the link is lost, [`Update::Lost`] is returned and events are ignored until the slave
+    /// has sent its state again.
+    pub fn poll(&mut self, link: &mut impl Transport, now: u64) -> Option<Update<N>> {
+        if let Some(update) = self.service(link, now) {
+            return Some(update);
+        }
+        while let Some(byte) = link.read() {
+            let Some(frame) = self.parser.push(byte) else {
+                continue;
+            };
+            if let Some(update) = self.handle(&frame, link, now) {
+                return Some(update);
             }
+        }
+        None
+    }
 
-            if self.recvd >= self.buffer.len() {
-                self.recvd = 0;
-                self.is_waiting = false;
-                return Some(&self.buffer);
+    /// Follows the state of the link and sends the pending message. Returns [`Update::Lost`]
+    /// once the link is lost.
+    pub fn service(&mut self, link: &mut impl Transport, now: u64) -> Option<Update<N>> {
+        if self.monitor.update(now) == Some(LinkState::Lost) {
+            self.events.desync();
+            // The slave may come back with other firmware, and its clock restarted. The message in
+            // flight is sent again under a new sequence number, or not at all if it isn't an
+            // update and the new firmware is incompatible.
+            self.peer = None;
+            self.in_flight = None;
+            self.clock = ClockSync::new();
+            return Some(Update::Lost);
+        }
+
+        // Modules on the bus only send when polled, so only the other half's clock is followed.
+        if self.device == 0 && self.is_compatible() && now - self.t_last_sync >= SYNC_US {
+            if let Some(sync) = Frame::new(FrameKind::Sync, 0, &now.to_le_bytes()) {
+                link.write(&sync.encode());
             }
+            self.t_last_sync = now;
+        }
+
+        if self.peer.is_some()
+            && (self.in_flight.is_none() || now - self.t_last_message_sent >= MESSAGE_RETRANSMIT_US)
+        {
+            self.send_next(link, now);
         }
         None
     }
+
+    /// Handles a frame received from the slave, and returns the update it carries.
+    pub fn handle(
+        &mut self,
+        frame: &Frame,
+        link: &mut impl Transport,
+        now: u64,
+    ) -> Option<Update<N>> {
+        self.monitor.heard(now);
+        if frame.kind == FrameKind::Hello {
+            self.peer = Greeting::from_frame(frame);
+            if frame.seq == HELLO_REQUEST {
+                link.write(&self.hello.to_frame(false).for_device(self.device).encode());
+            }
+            return None;
+        }
+        match frame.kind {
+            FrameKind::Ack if Some(frame.seq) == self.in_flight => {
+                self.outbox.remove(0);
+                self.in_flight = None;
+                self.send_next(link, now);
+                return None;
+            }
+            FrameKind::UpdateStatus if self.can_update() => {
+                return UpdateStatus::from_frame(frame).map(Update::Firmware);
+            }
+            _ => {}
+        }
+        if !self.is_compatible() {
+            if self.peer.is_none() {
+                // The slave was connected before the master started, so ask who it is.
+                link.write(&self.hello.to_frame(true).for_device(self.device).encode());
+            }
+            return None;
+        }
+        match frame.kind {
+            FrameKind::Event => {
+                let event = Event::from_frame(frame)?;
+                let accepted = self.events.accept(frame.seq);
+                self.ack_events(link);
+                if accepted {
+                    return Some(Update::Event(event));
+                }
+            }
+            FrameKind::SyncReply if frame.payload.len() == 16 => {
+                let t_sent = u64::from_le_bytes(frame.payload[..8].try_into().unwrap());
+                let t_remote = u64::from_le_bytes(frame.payload[8..].try_into().unwrap());
+                self.clock.sample(t_sent, t_remote, now);
+                self.round_trip.record(now.saturating_sub(t_sent));
+            }
+            FrameKind::State if frame.payload.len() == N + 1 => {
+                let applies = self.events.sync(frame.payload[0], frame.seq);
+                self.ack_events(link);
+                if applies {
+                    let mut state = [0; N];
+                    state.copy_from_slice(&frame.payload[1..]);
+                    return Some(Update::State(state));
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /// Frames that were rejected because they were corrupted.
+    pub fn rejected(&self) -> u32 {
+        self.parser.rejected
+    }
+
+    pub fn link_state(&self) -> LinkState {
+        self.monitor.state()
+    }
+
+    /// Converts the time `remote_us` of an event of the slave to the master's clock, given the
+    /// time `now` of the master soon after. Returns `None` until the slave's clock is known.
+    pub fn local_time(&self, remote_us: u32, now: u64) -> Option<u64> {
+        self.clock.to_local(remote_us, now)
+    }
+
+    /// The round trips of the link to the slave.
+    pub fn round_trip(&self) -> &Latency {
+        &self.round_trip
+    }
+
+    /// The last hello of the slave, until the link is lost.
+    pub fn peer(&self) -> Option<Greeting> {
+        self.peer
+    }
+
+    /// Whether the slave has introduced itself, and can be talked to.
+    pub fn is_compatible(&self) -> bool {
+        matches!(self.peer, Some(Greeting::Hello(peer)) if self.hello.accepts(&peer))
+    }
+
+    /// Whether the other half has introduced itself, and can be updated whatever its protocol
+    /// version.
+    pub fn can_update(&self) -> bool {
+        self.device == 0 && self.peer.is_some()
+    }
+
+    fn can_send(&self, message: &Message) -> bool {
+        self.is_compatible() || is_update(message) && self.can_update()
+    }
+}
+
+fn is_update(message: &Message) -> bool {
+    matches!(message, Message::Update(_))
+}
+
+/// The slave's end of the split link. Pushes events to the master and receives its messages.
+///
+/// Hellos are sent instead of events and states until the master has sent one that the slave
+/// accepts. Modules on the bus only send when polled.
+pub struct SlaveLink {
+    device: u8,
+    hello: Hello,
+    /// The last hello of the master.
+    peer: Option<Greeting>,
+    /// Whether this is a module on the bus.
+    bus: bool,
+    /// Whether a module has been polled since it last sent.
+    polled: bool,
+    t_last_hello_sent: Option<u64>,
+    parser: FrameParser,
+    monitor: LinkMonitor,
+    /// The sequence number of the last message received, to drop retransmissions.
+    last_msg_seq: Option<u8>,
+    events: EventQueue,
+    t_last_event_sent: u64,
+    t_last_state_sent: u64,
+}
+
+impl SlaveLink {
+    /// Creates the slave's end of the link, which introduces itself with `hello`.
+    pub fn new(hello: Hello) -> Self {
+        SlaveLink {
+            device: 0,
+            hello,
+            peer: None,
+            bus: false,
+            polled: false,
+            t_last_hello_sent: None,
+            parser: FrameParser::new(),
+            monitor: LinkMonitor::new(),
+            last_msg_seq: None,
+            events: EventQueue::new(),
+            t_last_event_sent: 0,
+            t_last_state_sent: 0,
+        }
+    }
+
+    /// Creates the end of a module on the bus, with the id `device`.
+    #[allow(unused)]
+    pub fn on_bus(device: u8, hello: Hello) -> Self {
+        SlaveLink {
+            device,
+            bus: true,
+            ..Self::new(hello)
+        }
+    }
+
+    /// Queues an event for the master.
+    pub fn push_event(&mut self, event: Event) {
+        self.events.push(event);
+    }
+
+    /// Sends the next queued event, retransmits the unacknowledged ones once the master has been
+    /// quiet for too long and periodically sends the full `state`. Incoming messages are
+    /// acknowledged, and the first new one is returned, leaving the rest of the input for the
+    /// next call.
+    pub fn poll(&mut self, link: &mut impl Transport, now: u64, state: &[u8]) -> Option<Message> {
+        let message = self.receive(link, now);
+        if self.bus && !core::mem::take(&mut self.polled) {
+            return message;
+        }
+        if self.is_compatible() {
+            self.transmit(link, now, state);
+        } else if self.bus || self.t_last_hello_sent.map_or(true, |t| now - t >= STATE_US) {
+            link.write(&self.hello.to_frame(true).for_device(self.device).encode());
+            self.t_last_hello_sent = Some(now);
+        }
+        message
+    }
+
+    fn transmit(&mut self, link: &mut impl Transport, now: u64, state: &[u8]) {
+        // A module sends everything it has queued when polled.
+        let burst = if self.bus { EVENT_QUEUE_CAPACITY } else { 1 };
+        for _ in 0..burst {
+            let mut next = self.events.next_to_send();
+            if next.is_none()
+                && !self.events.is_empty()
+                && now - self.t_last_event_sent > RETRANSMIT_US
+            {
+                self.events.rewind();
+                next = self.events.next_to_send();
+            }
+            let Some((seq, event)) = next else {
+                break;
+            };
+            link.write(&event.to_frame(seq).for_device(self.device).encode());
+            self.t_last_event_sent = now;
+        }
+
+        if now - self.t_last_state_sent >= STATE_US {
+            let (oldest, next_seq) = self.events.window();
+            let mut payload = Vec::<u8, MAX_PAYLOAD>::new();
+            _ = payload.push(oldest);
+            if payload.extend_from_slice(state).is_ok() {
+                if let Some(frame) = Frame::new(FrameKind::State, next_seq, &payload) {
+                    link.write(&frame.for_device(self.device).encode());
+                }
+            }
+            self.t_last_state_sent = now;
+        }
+    }
+
+    /// Handles the input, and returns the first new message.
+    fn receive(&mut self, link: &mut impl Transport, now: u64) -> Option<Message> {
+        while let Some(byte) = link.read() {
+            let Some(frame) = self.parser.push(byte) else {
+                continue;
+            };
+            if frame.device != self.device {
+                continue;
+            }
+            self.monitor.heard(now);
+            match frame.kind {
+                FrameKind::Ack => self.events.ack(frame.seq),
+                FrameKind::Poll => self.polled = true,
+                FrameKind::Sync if !self.bus && frame.payload.len() == 8 => {
+                    let mut payload = [0; 16];
+                    payload[..8].copy_from_slice(&frame.payload);
+                    payload[8..].copy_from_slice(&now.to_le_bytes());
+                    if let Some(reply) = Frame::new(FrameKind::SyncReply, 0, &payload) {
+                        link.write(&reply.encode());
+                    }
+                }
+                FrameKind::Hello => {
+                    self.peer = Greeting::from_frame(&frame);
+                    if frame.seq == HELLO_REQUEST {
+                        link.write(&self.hello.to_frame(false).for_device(self.device).encode());
+                    }
+                }
+                // Messages are refused until the master is known to be compatible, but updates
+                // are taken from a master of any protocol version.
+                kind if !(self.is_compatible()
+                    || kind == FrameKind::Update && self.can_update()) => {}
+                _ => {
+                    let Some(message) = Message::from_frame(&frame) else {
+                        continue;
+                    };
+                    if let Some(ack) = Frame::new(FrameKind::Ack, frame.seq, &[]) {
+                        link.write(&ack.for_device(self.device).encode());
+                    }
+                    if self.last_msg_seq != Some(frame.seq) {
+                        self.last_msg_seq = Some(frame.seq);
+                        return Some(message);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Events that were dropped because too many were waiting for the master.
+    pub fn dropped_events(&self) -> u32 {
+        self.events.dropped
+    }
+
+    /// Updates the state of the link for the time `now`, and returns it if it changed.
+    pub fn update_link_state(&mut self, now: u64) -> Option<LinkState> {
+        let state = self.monitor.update(now);
+        if state == Some(LinkState::Lost) {
+            // The master may come back with other firmware.
+            self.peer = None;
+        }
+        state
+    }
+
+    pub fn link_state(&self) -> LinkState {
+        self.monitor.state()
+    }
+
+    /// The last hello of the master, until the link is lost.
+    pub fn peer(&self) -> Option<Greeting> {
+        self.peer
+    }
+
+    /// Whether the master has introduced itself, and can be talked to.
+    pub fn is_compatible(&self) -> bool {
+        matches!(self.peer, Some(Greeting::Hello(peer)) if self.hello.accepts(&peer))
+    }
+
+    /// Whether the master has introduced itself, and can update this half whatever its protocol
+    /// version.
+    pub fn can_update(&self) -> bool {
+        !self.bus && self.peer.is_some()
+    }
+
+    /// Tells the master how an update was taken.
+    pub fn report_update(&self, link: &mut impl Transport, status: UpdateStatus) {
+        link.write(&status.to_frame().encode());
+    }
+}
+
+/// The events of the slave that the master hasn't acknowledged yet.
+///
+/// When the queue overflows the oldest event is dropped, and the master recovers from the next
+/// state sent by the slave instead.
+pub struct EventQueue {
+    events: Deque<(u8, Event), EVENT_QUEUE_CAPACITY>,
+    next_seq: u8,
+    /// Queued events that have been sent since the last rewind.
+    sent: usize,
+    /// Events that were dropped because the queue was full.
+    pub dropped: u32,
+}
+
+impl EventQueue {
+    pub fn new() -> Self {
+        EventQueue {
+            events: Deque::new(),
+            next_seq: 0,
+            sent: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn push(&mut self, event: Event) {
+        if self.events.is_full() {
+            self.events.pop_front();
+            self.sent = self.sent.saturating_sub(1);
+            self.dropped += 1;
+        }
+        // There is room, since the queue was just made smaller if it was full.
+        _ = self.events.push_back((self.next_seq, event));
+        self.next_seq = self.next_seq.wrapping_add(1);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Drops every event up to and including `seq`.
+    pub fn ack(&mut self, seq: u8) {
+        while let Some(&(first, _)) = self.events.front() {
+            // Sequence numbers wrap around, so only those up to half the range behind `seq` are
+            // taken to be acknowledged.
+            if (seq.wrapping_sub(first) as i8) < 0 {
+                break;
+            }
+            self.events.pop_front();
+            self.sent = self.sent.saturating_sub(1);
+        }
+    }
+
+    /// Returns the first event that hasn't been sent since the last rewind.
+    pub fn next_to_send(&mut self) -> Option<(u8, Event)> {
+        let next = self.events.iter().nth(self.sent).copied();
+        if next.is_some() {
+            self.sent += 1;
+        }
+        next
+    }
+
+    /// Makes every queued event be sent again.
+    pub fn rewind(&mut self) {
+        self.sent = 0;
+    }
+
+    /// The sequence numbers of the oldest unacknowledged event and of the next event.
+    pub fn window(&self) -> (u8, u8) {
+        let oldest = self.events.front().map_or(self.next_seq, |&(seq, _)| seq);
+        (oldest, self.next_seq)
+    }
+}
+
+/// Keeps the events of the slave in order on the master.
+///
+/// Events are only accepted once a state of the slave has been received, since they are changes
+/// to it.
+pub struct EventTracker {
+    expected: u8,
+    synced: bool,
+}
+
+impl EventTracker {
+    pub fn new() -> Self {
+        EventTracker {
+            expected: 0,
+            synced: false,
+        }
+    }
+
+    /// Ignores events until the next state, after the link was lost.
+    pub fn desync(&mut self) {
+        self.synced = false;
+    }
+
+    /// Returns `true` if the event with `seq` is the next one. Anything else is a retransmission
+    /// or comes after a lost event, and the slave sends it again later.
+    pub fn accept(&mut self, seq: u8) -> bool {
+        if self.synced && seq == self.expected {
+            self.expected = self.expected.wrapping_add(1);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The sequence number of the last event received in order, unless out of sync.
+    pub fn last(&self) -> Option<u8> {
+        self.synced.then_some(self.expected.wrapping_sub(1))
+    }
+
+    /// Returns `true` if a state sent while the slave's unacknowledged events were
+    /// `oldest..next` applies. It does once every event before it has been received, and also
+    /// when the slave no longer has the events the master is waiting for, in which case the
+    /// master continues from the state.
+    pub fn sync(&mut self, oldest: u8, next: u8) -> bool {
+        if !self.synced {
+            self.synced = true;
+            self.expected = next;
+            return true;
+        }
+        if self.expected == next {
+            return true;
+        }
+        let waiting_for_queued = self.expected.wrapping_sub(oldest) <= next.wrapping_sub(oldest);
+        if !waiting_for_queued {
+            self.expected = next;
+        }
+        !waiting_for_queued
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mock::Loopback,
+        protocol::{Command, Peer, UpdateStep, PROTOCOL_VERSION},
+    };
+
+    fn event(row: u8) -> Event {
+        Event {
+            row,
+            col: 0,
+            pressed: true,
+            time_us: 0,
+        }
+    }
+
+    #[test]
+    fn events_are_sent_until_acknowledged() {
+        let mut queue = EventQueue::new();
+        let mut tracker = EventTracker::new();
+        assert!(tracker.sync(0, 0));
+        queue.push(event(0));
+        queue.push(event(1));
+
+        // The first event is lost on the way.
+        assert_eq!(queue.next_to_send(), Some((0, event(0))));
+        let (seq, _) = queue.next_to_send().unwrap();
+        assert!(!tracker.accept(seq));
+        queue.ack(tracker.last().unwrap());
+        assert_eq!(queue.window(), (0, 2));
+        assert_eq!(queue.next_to_send(), None);
+
+        queue.rewind();
+        for expected in [event(0), event(1)] {
+            let (seq, event) = queue.next_to_send().unwrap();
+            assert!(tracker.accept(seq));
+            assert_eq!(event, expected);
+            queue.ack(tracker.last().unwrap());
+        }
+        assert!(queue.is_empty());
+        assert_eq!(queue.window(), (2, 2));
+    }
+
+    #[test]
+    fn state_waits_for_queued_events() {
+        let mut tracker = EventTracker::new();
+        assert!(tracker.sync(0, 0));
+        assert_eq!(tracker.last(), Some(255));
+        // Events 0 and 1 are still on the way.
+        assert!(!tracker.sync(0, 2));
+        assert!(tracker.accept(0));
+        assert!(tracker.accept(1));
+        assert!(tracker.sync(2, 2));
+    }
+
+    #[test]
+    fn state_recovers_from_dropped_events() {
+        let mut queue = EventQueue::new();
+        for row in 0..EVENT_QUEUE_CAPACITY as u8 + 3 {
+            queue.push(event(row));
+        }
+        assert_eq!(queue.dropped, 3);
+
+        // The master waits for event 0, which is gone, so it continues from the state.
+        let mut tracker = EventTracker::new();
+        assert!(tracker.sync(0, 0));
+        let (oldest, next) = queue.window();
+        assert!(tracker.sync(oldest, next));
+        queue.ack(tracker.last().unwrap());
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn events_wait_for_state_after_loss() {
+        let mut tracker = EventTracker::new();
+        assert!(!tracker.accept(0));
+        assert_eq!(tracker.last(), None);
+        assert!(tracker.sync(0, 0));
+        assert!(tracker.accept(0));
+
+        // The slave restarted while the link was down.
+        tracker.desync();
+        assert!(!tracker.accept(1));
+        assert!(tracker.sync(0, 0));
+        assert!(tracker.accept(0));
+    }
+
+    #[test]
+    fn link_state_follows_silence() {
+        let mut monitor = LinkMonitor::new();
+        assert_eq!(monitor.state(), LinkState::Lost);
+        assert_eq!(monitor.update(0), None);
+        monitor.heard(0);
+        assert_eq!(monitor.update(1), Some(LinkState::Connected));
+        assert_eq!(monitor.update(DEGRADED_US - 1), None);
+        assert_eq!(monitor.update(DEGRADED_US), Some(LinkState::Degraded));
+        assert_eq!(monitor.update(LOST_US), Some(LinkState::Lost));
+        monitor.heard(LOST_US + 5);
+        assert_eq!(monitor.update(LOST_US + 5), Some(LinkState::Connected));
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        let mut queue = EventQueue::new();
+        let mut tracker = EventTracker::new();
+        assert!(tracker.sync(0, 0));
+        for _ in 0..300 {
+            queue.push(event(0));
+            let (seq, _) = queue.next_to_send().unwrap();
+            assert!(tracker.accept(seq));
+            queue.ack(tracker.last().unwrap());
+            assert!(queue.is_empty());
+        }
+    }
+
+    struct Halves {
+        master: ComLink<4>,
+        slave: SlaveLink,
+        master_end: Loopback,
+        slave_end: Loopback,
+        now: u64,
+    }
+
+    impl Halves {
+        fn new() -> Self {
+            let (master_end, slave_end) = Loopback::pair();
+            Halves {
+                master: ComLink::new(Hello::new(Peer::Master, 2, 2)),
+                slave: SlaveLink::new(Hello::new(Peer::Slave, 2, 2)),
+                master_end,
+                slave_end,
+                now: 0,
+            }
+        }
+
+        /// Runs both halves for `us` in steps of 100 µs, and returns what each of them received.
+        fn run(&mut self, us: u64, state: &[u8; 4]) -> (Vec<Update<4>, 64>, Vec<Message, 8>) {
+            let (mut updates, mut messages) = (Vec::new(), Vec::new());
+            for _ in 0..us / 100 {
+                self.now += 100;
+                if let Some(message) = self.slave.poll(&mut self.slave_end, self.now, state) {
+                    messages.push(message).unwrap();
+                }
+                while let Some(update) = self.master.poll(&mut self.master_end, self.now) {
+                    updates.push(update).unwrap();
+                }
+            }
+            (updates, messages)
+        }
+    }
+
+    #[test]
+    fn master_follows_slave() {
+        let mut halves = Halves::new();
+        let (updates, _) = halves.run(STATE_US, &[1, 2, 3, 4]);
+        assert_eq!(updates, [Update::State([1, 2, 3, 4])]);
+        assert_eq!(halves.master.link_state(), LinkState::Connected);
+
+        // A tap shorter than a state period.
+        halves.slave.push_event(event(3));
+        halves.slave.push_event(Event {
+            pressed: false,
+            ..event(3)
+        });
+        let (updates, _) = halves.run(1_000, &[1, 2, 3, 4]);
+        assert_eq!(
+            updates,
+            [
+                Update::Event(event(3)),
+                Update::Event(Event {
+                    pressed: false,
+                    ..event(3)
+                })
+            ]
+        );
+    }
+
+    #[test]
+    fn events_survive_lost_bytes() {
+        let mut halves = Halves::new();
+        halves.run(STATE_US, &[0; 4]);
+        halves.slave.push_event(event(1));
+        halves.slave.push_event(event(2));
+        halves.slave_end.lose(3);
+        let (updates, _) = halves.run(2 * RETRANSMIT_US, &[0; 4]);
+        assert_eq!(updates, [Update::Event(event(1)), Update::Event(event(2))]);
+        assert_eq!(halves.master.rejected(), 0);
+    }
+
+    #[test]
+    fn messages_are_delivered_once() {
+        let mut halves = Halves::new();
+        halves.run(STATE_US, &[0; 4]);
+        assert!(halves.master.send(Message::Layer(1)));
+        assert!(halves.master.send(Message::Command(Command::Bootloader)));
+        // The first acknowledgement is lost, so the layer is sent again.
+        halves.slave_end.lose(1);
+        let (_, messages) = halves.run(3 * MESSAGE_RETRANSMIT_US, &[0; 4]);
+        assert_eq!(
+            messages,
+            [Message::Layer(1), Message::Command(Command::Bootloader)]
+        );
+    }
+
+    #[test]
+    fn master_releases_slave_when_lost() {
+        let mut halves = Halves::new();
+        halves.run(STATE_US, &[0; 4]);
+        let mut updates = Vec::<_, 64>::new();
+        while halves.now < 2 * LOST_US {
+            halves.now += 1_000;
+            if let Some(update) = halves.master.poll(&mut halves.master_end, halves.now) {
+                updates.push(update).unwrap();
+            }
+        }
+        assert_eq!(updates, [Update::Lost]);
+        assert_eq!(halves.master.link_state(), LinkState::Lost);
+
+        // Back from a restart, the slave sends its state again.
+        halves.slave = SlaveLink::new(Hello::new(Peer::Slave, 2, 2));
+        halves.slave.push_event(event(0));
+        let (updates, _) = halves.run(STATE_US, &[9; 4]);
+        assert_eq!(updates, [Update::State([9; 4])]);
+    }
+
+    #[test]
+    fn incompatible_slaves_are_refused() {
+        let mut halves = Halves::new();
+        halves.slave = SlaveLink::new(Hello::new(Peer::Slave, 3, 2));
+        assert!(halves.master.send(Message::Layer(1)));
+        let (updates, messages) = halves.run(3 * STATE_US, &[1, 2, 3, 4]);
+        assert_eq!(updates, []);
+        assert_eq!(messages, []);
+        assert_eq!(
+            halves.master.peer(),
+            Some(Greeting::Hello(Hello::new(Peer::Slave, 3, 2)))
+        );
+        assert!(!halves.master.is_compatible());
+        // The halves still hear each other, so the link isn't lost.
+        assert_eq!(halves.master.link_state(), LinkState::Connected);
+    }
+
+    #[test]
+    fn updates_reach_slaves_of_other_protocols() {
+        let mut halves = Halves::new();
+        halves.slave = SlaveLink::new(Hello {
+            protocol: PROTOCOL_VERSION + 1,
+            ..Hello::new(Peer::Slave, 2, 2)
+        });
+        assert!(halves.master.send(Message::Layer(1)));
+        assert!(halves.master.send(Message::Update(UpdateStep::Commit)));
+        let (_, messages) = halves.run(STATE_US, &[0; 4]);
+        assert!(!halves.master.is_compatible());
+        assert!(halves.master.can_update());
+        assert_eq!(messages, [Message::Update(UpdateStep::Commit)]);
+
+        halves
+            .slave
+            .report_update(&mut halves.slave_end, UpdateStatus::Verified);
+        let (updates, _) = halves.run(1_000, &[0; 4]);
+        assert_eq!(updates, [Update::Firmware(UpdateStatus::Verified)]);
+    }
+
+    #[test]
+    fn restarted_master_asks_for_hello() {
+        let mut halves = Halves::new();
+        halves.run(STATE_US, &[0; 4]);
+        assert!(halves.slave.is_compatible());
+
+        halves.master = ComLink::new(Hello::new(Peer::Master, 2, 2));
+        let (updates, _) = halves.run(2 * STATE_US, &[5; 4]);
+        assert!(halves.master.is_compatible());
+        assert_eq!(updates, [Update::State([5; 4])]);
+    }
+
+    #[test]
+    fn master_follows_slave_clock() {
+        let mut halves = Halves::new();
+        assert_eq!(halves.master.local_time(0, halves.now), None);
+        halves.run(4 * SYNC_US, &[0; 4]);
+        // Both halves share a clock here, so times come back unchanged.
+        let t = halves.now - 2_000;
+        let local = halves.master.local_time(t as u32, halves.now).unwrap();
+        assert!(local.abs_diff(t) <= 100, "{}", local);
+    }
+
+    #[test]
+    fn round_trips_are_measured() {
+        let mut halves = Halves::new();
+        assert_eq!(halves.master.round_trip().to_string(), "not measured");
+        halves.run(4 * SYNC_US, &[0; 4]);
+        let round_trip = halves.master.round_trip();
+        assert!(round_trip.count > 0);
+        assert!(round_trip.min <= round_trip.last && round_trip.last <= round_trip.max);
+    }
+
+    #[test]
+    fn latency_statistics() {
+        let mut latency = Latency::new();
+        for us in [300, 100, 200] {
+            latency.record(us);
+        }
+        assert_eq!(
+            latency.to_string(),
+            "last 200 us, min 100 us, mean 200 us, max 300 us"
+        );
+    }
 }
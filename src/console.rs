@@ -0,0 +1,67 @@
+use core::fmt;
+
+use heapless::Vec;
+use usb_device::{bus::UsbBus, UsbError};
+use usbd_serial::SerialPort;
+
+/// A debug console on a USB serial port next to the keyboard.
+///
+/// Output is buffered and flushed a bit at a time by [`Console::poll`], so writing to the console
+/// never blocks the main loop. Output that doesn't fit in the buffer is discarded.
+pub struct Console<'a, B: UsbBus> {
+    port: SerialPort<'a, B>,
+    pending: Vec<u8, 2048>,
+}
+
+impl<'a, B: UsbBus> Console<'a, B> {
+    pub fn new(port: SerialPort<'a, B>) -> Self {
+        Console {
+            port,
+            pending: Vec::new(),
+        }
+    }
+
+    /// The serial port, which must be polled together with the other USB classes.
+    pub fn port(&mut self) -> &mut SerialPort<'a, B> {
+        &mut self.port
+    }
+
+    /// Flushes pending output and returns the next command byte, if any.
+    pub fn poll(&mut self) -> Option<u8> {
+        let mut byte = [0];
+        match self.read(&mut byte) {
+            1 => Some(byte[0]),
+            _ => None,
+        }
+    }
+
+    /// Flushes pending output and reads data that isn't commands, like a firmware image, into
+    /// `buf`. Returns the number of bytes read.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        if !self.pending.is_empty() {
+            match self.port.write(&self.pending) {
+                Ok(count) => {
+                    self.pending.rotate_left(count);
+                    self.pending.truncate(self.pending.len() - count);
+                }
+                Err(UsbError::WouldBlock) => {}
+                Err(_) => self.pending.clear(),
+            }
+        }
+
+        if buf.is_empty() {
+            return 0;
+        }
+        self.port.read(buf).unwrap_or(0)
+    }
+}
+
+impl<'a, B: UsbBus> fmt::Write for Console<'a, B> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let free = self.pending.capacity() - self.pending.len();
+        _ = self
+            .pending
+            .extend_from_slice(&s.as_bytes()[..s.len().min(free)]);
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+/// The debouncing algorithm and its time windows, in microseconds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(unused)]
+pub enum Algorithm {
+    /// The raw state is passed through as is.
+    None,
+    /// Both presses and releases are reported once the raw state has been stable for `window_us`.
+    SymmetricDefer { window_us: u32 },
+    /// Presses are reported immediately, releases once the key has been released for `window_us`.
+    EagerPress { window_us: u32 },
+    /// A per-key counter that integrates the time the raw state disagrees with the reported
+    /// state, and counts down while they agree. The state flips once the counter reaches
+    /// `press_us` or `release_us`, so short bounces don't restart the window.
+    Counter { press_us: u32, release_us: u32 },
+}
+
+#[derive(Clone, Copy, Debug)]
+struct KeyState {
+    /// The debounced state.
+    pressed: bool,
+    /// The raw state in the previous update.
+    raw: bool,
+    /// When the raw state last changed.
+    t_raw_change: u64,
+    /// Time integrated by [`Algorithm::Counter`].
+    counter: u32,
+}
+
+/// Filters the raw scans of a button matrix.
+pub struct Debouncer<const COLS: usize, const ROWS: usize> {
+    algorithm: Algorithm,
+    keys: [[KeyState; COLS]; ROWS],
+    t_last_update: u64,
+}
+
+impl<const COLS: usize, const ROWS: usize> Debouncer<COLS, ROWS> {
+    pub fn new(algorithm: Algorithm) -> Self {
+        Debouncer {
+            algorithm,
+            keys: [[KeyState {
+                pressed: false,
+                raw: false,
+                t_raw_change: 0,
+                counter: 0,
+            }; COLS]; ROWS],
+            t_last_update: 0,
+        }
+    }
+
+    /// Changes the time windows of the algorithm to `window_us`, keeping the state of the keys.
+    pub fn set_window(&mut self, window_us: u32) {
+        self.algorithm = match self.algorithm {
+            Algorithm::None => Algorithm::None,
+            Algorithm::SymmetricDefer { .. } => Algorithm::SymmetricDefer { window_us },
+            Algorithm::EagerPress { .. } => Algorithm::EagerPress { window_us },
+            Algorithm::Counter { .. } => Algorithm::Counter {
+                press_us: window_us,
+                release_us: window_us,
+            },
+        };
+    }
+
+    /// Feeds a raw scan taken at `now` (in microseconds) and returns the debounced state.
+    pub fn update(&mut self, raw: &[[bool; COLS]; ROWS], now: u64) -> [[bool; COLS]; ROWS] {
+        let dt = now.saturating_sub(self.t_last_update).min(u32::MAX as u64) as u32;
+        self.t_last_update = now;
+
+        let mut debounced = [[false; COLS]; ROWS];
+        for (ri, row) in self.keys.iter_mut().enumerate() {
+            for (ci, key) in row.iter_mut().enumerate() {
+                let raw = raw[ri][ci];
+                let prev_raw = key.raw;
+                if raw != key.raw {
+                    key.raw = raw;
+                    key.t_raw_change = now;
+                }
+                let stable_for = now - key.t_raw_change;
+
+                match self.algorithm {
+                    Algorithm::None => key.pressed = raw,
+                    Algorithm::SymmetricDefer { window_us } => {
+                        if raw != key.pressed && stable_for >= window_us as u64 {
+                            key.pressed = raw;
+                        }
+                    }
+                    Algorithm::EagerPress { window_us } => {
+                        if raw && !key.pressed {
+                            key.pressed = true;
+                        } else if !raw && key.pressed && stable_for >= window_us as u64 {
+                            key.pressed = false;
+                        }
+                    }
+                    Algorithm::Counter {
+                        press_us,
+                        release_us,
+                    } => {
+                        // The previous raw state is assumed to have held since the last update.
+                        if prev_raw != key.pressed {
+                            key.counter = key.counter.saturating_add(dt);
+                            let limit = if prev_raw { press_us } else { release_us };
+                            if key.counter >= limit {
+                                key.pressed = prev_raw;
+                                key.counter = 0;
+                            }
+                        } else {
+                            key.counter = key.counter.saturating_sub(dt);
+                        }
+                    }
+                }
+                debounced[ri][ci] = key.pressed;
+            }
+        }
+        debounced
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds a single key the given `(time, raw)` samples and returns the debounced states.
+    fn run(algorithm: Algorithm, samples: &[(u64, bool)]) -> std::vec::Vec<bool> {
+        let mut debouncer = Debouncer::<1, 1>::new(algorithm);
+        samples
+            .iter()
+            .map(|&(t, raw)| debouncer.update(&[[raw]], t)[0][0])
+            .collect()
+    }
+
+    /// A press that bounces for 2ms, is held until 20ms and bounces again when released.
+    const BOUNCY: [(u64, bool); 12] = [
+        (0, false),
+        (500, true),
+        (1000, false),
+        (1500, true),
+        (2000, false),
+        (2500, true),
+        (10000, true),
+        (20000, false),
+        (20500, true),
+        (21000, false),
+        (25000, false),
+        (30000, false),
+    ];
+
+    #[test]
+    fn none_passes_through() {
+        let out = run(Algorithm::None, &BOUNCY);
+        let raw: std::vec::Vec<bool> = BOUNCY.iter().map(|&(_, raw)| raw).collect();
+        assert_eq!(out, raw);
+    }
+
+    #[test]
+    fn symmetric_defer() {
+        let out = run(Algorithm::SymmetricDefer { window_us: 5000 }, &BOUNCY);
+        assert_eq!(
+            out,
+            [false, false, false, false, false, false, true, true, true, true, true, false]
+        );
+    }
+
+    #[test]
+    fn eager_press() {
+        let out = run(Algorithm::EagerPress { window_us: 5000 }, &BOUNCY);
+        assert_eq!(
+            out,
+            [false, true, true, true, true, true, true, true, true, true, true, false]
+        );
+    }
+
+    #[test]
+    fn counter() {
+        let out = run(
+            Algorithm::Counter {
+                press_us: 1000,
+                release_us: 4000,
+            },
+            &BOUNCY,
+        );
+        assert_eq!(
+            out,
+            [false, false, false, false, false, false, true, true, true, true, false, false]
+        );
+    }
+
+    #[test]
+    fn counter_ignores_single_spikes() {
+        let samples: std::vec::Vec<(u64, bool)> = (0..20).map(|i| (i * 500, i % 4 == 1)).collect();
+        let out = run(
+            Algorithm::Counter {
+                press_us: 1000,
+                release_us: 1000,
+            },
+            &samples,
+        );
+        assert!(out.iter().all(|&pressed| !pressed));
+    }
+
+    #[test]
+    fn keys_are_independent() {
+        let mut debouncer = Debouncer::<2, 1>::new(Algorithm::SymmetricDefer { window_us: 1000 });
+        assert_eq!(debouncer.update(&[[true, false]], 0), [[false, false]]);
+        assert_eq!(debouncer.update(&[[true, true]], 500), [[false, false]]);
+        assert_eq!(debouncer.update(&[[true, true]], 1000), [[true, false]]);
+        assert_eq!(debouncer.update(&[[true, true]], 1500), [[true, true]]);
+    }
+
+    #[test]
+    fn window_changes_keep_held_keys() {
+        let mut debouncer = Debouncer::<1, 1>::new(Algorithm::EagerPress { window_us: 5_000 });
+        assert_eq!(debouncer.update(&[[true]], 0), [[true]]);
+        debouncer.set_window(10_000);
+        assert_eq!(debouncer.update(&[[true]], 1_000), [[true]]);
+        assert_eq!(debouncer.update(&[[false]], 2_000), [[true]]);
+        assert_eq!(debouncer.update(&[[false]], 8_000), [[true]]);
+        assert_eq!(debouncer.update(&[[false]], 12_000), [[false]]);
+    }
+}
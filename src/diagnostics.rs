@@ -0,0 +1,205 @@
+use core::fmt;
+
+use crate::buttonmatrix::ScanError;
+
+/// Presses that are released again within this many microseconds are counted as chatter.
+pub const CHATTER_US: u64 = 1_000;
+/// Keys that have been held for longer than this many microseconds are reported as stuck.
+pub const STUCK_US: u64 = 5 * 60 * 1_000_000;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KeyStats {
+    /// Debounced presses.
+    pub presses: u32,
+    /// Raw state changes that were filtered out by the debouncer.
+    pub bounces: u32,
+    /// Raw presses that were released again within [`CHATTER_US`].
+    pub chatters: u32,
+    raw_changes: u32,
+    debounced_changes: u32,
+    raw: bool,
+    t_raw_press: u64,
+    /// When the key was pressed, if it's currently pressed.
+    t_press: Option<u64>,
+}
+
+impl KeyStats {
+    /// How long the key has been held, if it's currently pressed.
+    pub fn held_for(&self, now: u64) -> Option<u64> {
+        self.t_press.map(|t| now.saturating_sub(t))
+    }
+
+    pub fn is_stuck(&self, now: u64) -> bool {
+        self.held_for(now).is_some_and(|held| held >= STUCK_US)
+    }
+
+    fn is_noteworthy(&self, now: u64) -> bool {
+        self.bounces > 0 || self.chatters > 0 || self.is_stuck(now)
+    }
+}
+
+/// Per-key statistics used to find failing switches.
+pub struct Diagnostics<const COLS: usize, const ROWS: usize> {
+    keys: [[KeyStats; COLS]; ROWS],
+    /// Scans that failed because of a pin error.
+    pub scan_errors: u32,
+    /// The most recent scan error, and when it happened.
+    pub last_error: Option<(ScanError, u64)>,
+}
+
+impl<const COLS: usize, const ROWS: usize> Diagnostics<COLS, ROWS> {
+    pub fn new() -> Self {
+        Diagnostics {
+            keys: [[KeyStats::default(); COLS]; ROWS],
+            scan_errors: 0,
+            last_error: None,
+        }
+    }
+
+    /// Records a scan that failed at `now`.
+    pub fn record_error(&mut self, err: ScanError, now: u64) {
+        self.scan_errors += 1;
+        self.last_error = Some((err, now));
+    }
+
+    /// Records a scan, both before and after debouncing, taken at `now` (in microseconds).
+    pub fn record(
+        &mut self,
+        raw: &[[bool; COLS]; ROWS],
+        debounced: &[[bool; COLS]; ROWS],
+        now: u64,
+    ) {
+        for (ri, row) in self.keys.iter_mut().enumerate() {
+            for (ci, key) in row.iter_mut().enumerate() {
+                let raw = raw[ri][ci];
+                if raw != key.raw {
+                    key.raw = raw;
+                    key.raw_changes += 1;
+                    if raw {
+                        key.t_raw_press = now;
+                    } else if now - key.t_raw_press < CHATTER_US {
+                        key.chatters += 1;
+                    }
+                }
+
+                let pressed = debounced[ri][ci];
+                if pressed != key.t_press.is_some() {
+                    key.debounced_changes += 1;
+                    if pressed {
+                        key.presses += 1;
+                        key.t_press = Some(now);
+                    } else {
+                        key.t_press = None;
+                    }
+                }
+                key.bounces = key.raw_changes.saturating_sub(key.debounced_changes);
+            }
+        }
+    }
+
+    #[allow(unused)]
+    pub fn key(&self, row: usize, col: usize) -> &KeyStats {
+        &self.keys[row][col]
+    }
+
+    /// A report of the scan errors and of every key that has bounced, chattered or is stuck at
+    /// `now`.
+    pub fn report(&self, now: u64) -> Report<'_, COLS, ROWS> {
+        Report {
+            diagnostics: self,
+            now,
+        }
+    }
+}
+
+pub struct Report<'a, const COLS: usize, const ROWS: usize> {
+    diagnostics: &'a Diagnostics<COLS, ROWS>,
+    now: u64,
+}
+
+impl<'a, const COLS: usize, const ROWS: usize> fmt::Display for Report<'a, COLS, ROWS> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut healthy = true;
+        if let Some((err, t)) = self.diagnostics.last_error {
+            healthy = false;
+            write!(
+                f,
+                "{} scan errors, last: {:?} {}s ago\r\n",
+                self.diagnostics.scan_errors,
+                err,
+                self.now.saturating_sub(t) / 1_000_000
+            )?;
+        }
+        for (ri, row) in self.diagnostics.keys.iter().enumerate() {
+            for (ci, key) in row.iter().enumerate() {
+                if !key.is_noteworthy(self.now) {
+                    continue;
+                }
+                healthy = false;
+                write!(
+                    f,
+                    "key ({}, {}): {} presses, {} bounces, {} chatters",
+                    ri, ci, key.presses, key.bounces, key.chatters
+                )?;
+                if key.is_stuck(self.now) {
+                    let held = key.held_for(self.now).unwrap_or(0);
+                    write!(f, ", stuck for {}s", held / 1_000_000)?;
+                }
+                write!(f, "\r\n")?;
+            }
+        }
+        if healthy {
+            write!(f, "all keys healthy\r\n")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::ToString;
+
+    #[test]
+    fn counts_bounces_and_chatter() {
+        let mut diagnostics = Diagnostics::<1, 1>::new();
+        // A bouncy press that the debouncer turned into a single press.
+        diagnostics.record(&[[true]], &[[true]], 0);
+        diagnostics.record(&[[false]], &[[true]], 300);
+        diagnostics.record(&[[true]], &[[true]], 600);
+        diagnostics.record(&[[true]], &[[true]], 50_000);
+        diagnostics.record(&[[false]], &[[false]], 60_000);
+
+        let key = diagnostics.key(0, 0);
+        assert_eq!(key.presses, 1);
+        assert_eq!(key.bounces, 2);
+        assert_eq!(key.chatters, 1);
+        assert_eq!(key.held_for(70_000), None);
+    }
+
+    #[test]
+    fn reports_stuck_keys() {
+        let mut diagnostics = Diagnostics::<2, 1>::new();
+        diagnostics.record(&[[false, true]], &[[false, true]], 0);
+        assert_eq!(
+            diagnostics.report(1_000_000).to_string(),
+            "all keys healthy\r\n"
+        );
+        assert!(diagnostics.key(0, 1).is_stuck(STUCK_US));
+        assert_eq!(
+            diagnostics.report(STUCK_US).to_string(),
+            "key (0, 1): 1 presses, 0 bounces, 0 chatters, stuck for 300s\r\n"
+        );
+    }
+
+    #[test]
+    fn reports_scan_errors() {
+        let mut diagnostics = Diagnostics::<1, 1>::new();
+        diagnostics.record_error(ScanError::Row(0), 1_000_000);
+        diagnostics.record_error(ScanError::Col { row: 0, col: 0 }, 2_000_000);
+        assert_eq!(
+            diagnostics.report(5_000_000).to_string(),
+            "2 scan errors, last: Col { row: 0, col: 0 } 3s ago\r\n"
+        );
+    }
+}
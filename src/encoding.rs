@@ -1,37 +1,126 @@
-pub fn encode(state: &[[bool; 6]; 5]) -> [u8; 4] {
-    let mut encoded = [0; 4];
-    let mut flattened = [false; 6 * 5];
-    for ri in 0..5 {
-        for ci in 0..6 {
-            flattened[ri + ci * 5] = state[ri][ci];
+//! Bitmap encoding of the state of a half, for the split link.
+//!
+//! The keys are flattened column by column, so key `(ri, ci)` is bit `ri + ci * ROWS`, and packed
+//! eight to a byte starting from the least significant bit.
+
+/// The number of bytes needed to encode a state of `rows` by `cols` keys.
+pub const fn encoded_len(rows: usize, cols: usize) -> usize {
+    (rows * cols + 7) / 8
+}
+
+/// Encodes `state` into the start of `encoded` and returns the number of bytes written, or
+/// `None` if `encoded` is too short.
+pub fn encode<const COLS: usize, const ROWS: usize>(
+    state: &[[bool; COLS]; ROWS],
+    encoded: &mut [u8],
+) -> Option<usize> {
+    let len = encoded_len(ROWS, COLS);
+    let encoded = encoded.get_mut(..len)?;
+    encoded.fill(0);
+    for (ri, row) in state.iter().enumerate() {
+        for (ci, &pressed) in row.iter().enumerate() {
+            let bit = ri + ci * ROWS;
+            encoded[bit / 8] |= (pressed as u8) << (bit % 8);
         }
     }
-    for (outidx, chunk) in flattened.chunks(8).enumerate() {
-        let mut packed = 0;
-        for i in 0..chunk.len() {
-            packed += (chunk[i] as u8) << (i as u8);
+    Some(len)
+}
+
+/// Decodes `encoded` into `state`. Returns `false`, leaving `state` as is, if `encoded` has the
+/// wrong length for it.
+pub fn decode<const COLS: usize, const ROWS: usize>(
+    encoded: &[u8],
+    state: &mut [[bool; COLS]; ROWS],
+) -> bool {
+    if encoded.len() != encoded_len(ROWS, COLS) {
+        return false;
+    }
+    for (ri, row) in state.iter_mut().enumerate() {
+        for (ci, pressed) in row.iter_mut().enumerate() {
+            let bit = ri + ci * ROWS;
+            *pressed = encoded[bit / 8] & (1 << (bit % 8)) != 0;
         }
-        encoded[outidx] = packed;
     }
+    true
+}
+
+/// Whether key `(ri, ci)` is pressed in a state of `rows` rows, for states whose size is only
+/// known at run time. Keys past the end of `encoded` are released.
+#[allow(unused)]
+pub fn is_pressed(encoded: &[u8], rows: usize, ri: usize, ci: usize) -> bool {
+    let bit = ri + ci * rows;
     encoded
+        .get(bit / 8)
+        .map_or(false, |byte| byte & (1 << (bit % 8)) != 0)
 }
 
-pub fn decode(encoded: &[u8; 4], state: &mut [[bool; 6]; 5]) {
-    let mut flattened = [false; 6 * 5];
-    for (i, byte) in encoded.iter().enumerate() {
-        let mut byte = *byte;
-        let mut offset = 0;
-        while byte != 0 {
-            if (byte & 1) == 1 {
-                flattened[i * 8 + offset] = true;
-            }
-            offset += 1;
-            byte >>= 1;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A xorshift generator, to get the same pseudo-random states on every run.
+    struct Rng(u32);
+
+    impl Rng {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+
+        fn state<const COLS: usize, const ROWS: usize>(&mut self) -> [[bool; COLS]; ROWS] {
+            core::array::from_fn(|_| core::array::from_fn(|_| self.next() & 1 == 1))
         }
     }
-    for ri in 0..5 {
-        for ci in 0..6 {
-            state[ri][ci] = flattened[ri + ci * 5];
+
+    fn round_trips<const COLS: usize, const ROWS: usize>() {
+        let mut rng = Rng(0x1234_5678);
+        for _ in 0..1000 {
+            let state = rng.state::<COLS, ROWS>();
+            let mut encoded = [0xFF; 16];
+            let len = encode(&state, &mut encoded).unwrap();
+            assert_eq!(len, encoded_len(ROWS, COLS));
+
+            let mut decoded = [[false; COLS]; ROWS];
+            assert!(decode(&encoded[..len], &mut decoded));
+            assert_eq!(decoded, state);
+            for (ri, row) in state.iter().enumerate() {
+                for (ci, &pressed) in row.iter().enumerate() {
+                    assert_eq!(is_pressed(&encoded[..len], ROWS, ri, ci), pressed);
+                }
+            }
         }
     }
+
+    #[test]
+    fn round_trip() {
+        round_trips::<6, 5>();
+        round_trips::<6, 4>();
+        round_trips::<7, 4>();
+        round_trips::<1, 1>();
+        round_trips::<8, 8>();
+    }
+
+    #[test]
+    fn layout_is_column_major() {
+        let mut state = [[false; 6]; 5];
+        state[1][0] = true;
+        state[0][1] = true;
+        state[4][5] = true;
+        let mut encoded = [0; 4];
+        assert_eq!(encode(&state, &mut encoded), Some(4));
+        assert_eq!(encoded, [0b0010_0010, 0, 0, 0b0010_0000]);
+    }
+
+    #[test]
+    fn wrong_lengths_are_rejected() {
+        let state = [[true; 7]; 4];
+        assert_eq!(encode(&state, &mut [0; 3]), None);
+
+        let mut decoded = [[false; 7]; 4];
+        assert!(!decode(&[0xFF; 3], &mut decoded));
+        assert!(!decode(&[0xFF; 5], &mut decoded));
+        assert_eq!(decoded, [[false; 7]; 4]);
+    }
 }
@@ -0,0 +1,203 @@
+//! The flash of the RP2040, written through the routines of its boot ROM.
+//!
+//! Nothing can be read from the flash while it's erased or programmed, so the code doing it runs
+//! from RAM with interrupts disabled, and only calls into the ROM.
+
+use core::ptr::addr_of_mut;
+
+use rp_pico::hal::rom_data;
+
+use crate::{
+    settings::Settings,
+    update::{self, Boot, Flash, Meta, ACTIVE, META, PAGE_LEN, SECTOR_LEN, UPDATE},
+};
+
+/// Where the flash is mapped for reading.
+const XIP_BASE: u32 = 0x1000_0000;
+/// The block erase command of the flash, and how much it erases.
+const BLOCK_ERASE: u8 = 0xD8;
+const BLOCK_LEN: u32 = 1 << 16;
+/// The register that resets the chip, and the value that does it.
+const AIRCR: u32 = 0xE000_ED0C;
+const SYSRESETREQ: u32 = 0x05FA_0004;
+
+/// A copy of the second stage bootloader, which sets up fast reads of the flash again once it's
+/// written.
+static mut BOOT2: [u32; 64] = [0; 64];
+/// The slots are swapped a sector at a time through these.
+static mut SECTOR_A: [u32; SECTOR_LEN as usize / 4] = [0; SECTOR_LEN as usize / 4];
+static mut SECTOR_B: [u32; SECTOR_LEN as usize / 4] = [0; SECTOR_LEN as usize / 4];
+
+/// The ROM routines, looked up while the flash can still be read.
+struct Rom {
+    connect_internal_flash: unsafe extern "C" fn(),
+    flash_exit_xip: unsafe extern "C" fn(),
+    flash_range_erase: unsafe extern "C" fn(u32, usize, u32, u8),
+    flash_range_program: unsafe extern "C" fn(u32, *const u8, usize),
+    flash_flush_cache: unsafe extern "C" fn(),
+    memcpy44: unsafe extern "C" fn(*mut u32, *const u32, u32) -> *mut u8,
+    boot2: unsafe extern "C" fn(),
+}
+
+pub struct RomFlash {
+    rom: Rom,
+}
+
+impl RomFlash {
+    pub fn new() -> Self {
+        let boot2 = unsafe {
+            let boot2 = addr_of_mut!(BOOT2) as *mut u32;
+            rom_data::memcpy44(boot2, XIP_BASE as *const u32, 256);
+            // The bootloader is Thumb code.
+            core::mem::transmute::<usize, unsafe extern "C" fn()>(boot2 as usize | 1)
+        };
+        RomFlash {
+            rom: Rom {
+                connect_internal_flash: rom_data::connect_internal_flash::ptr(),
+                flash_exit_xip: rom_data::flash_exit_xip::ptr(),
+                flash_range_erase: rom_data::flash_range_erase::ptr(),
+                flash_range_program: rom_data::flash_range_program::ptr(),
+                flash_flush_cache: rom_data::flash_flush_cache::ptr(),
+                memcpy44: rom_data::memcpy44::ptr(),
+                boot2,
+            },
+        }
+    }
+
+    /// Swaps the first `meta.sectors` sectors of the slots, writes `meta` and restarts.
+    ///
+    /// A power loss while the slots are swapped leaves neither firmware whole, and then only the
+    /// USB bootloader can bring the half back.
+    pub fn swap_and_restart(self, meta: Meta) -> ! {
+        let page = meta.page();
+        cortex_m::interrupt::disable();
+        unsafe {
+            swap_and_restart(
+                &self.rom,
+                meta.sectors * SECTOR_LEN,
+                page.as_ptr(),
+                addr_of_mut!(SECTOR_A) as *mut u32,
+                addr_of_mut!(SECTOR_B) as *mut u32,
+            )
+        }
+    }
+}
+
+impl Flash for RomFlash {
+    fn read(&mut self, offset: u32, buf: &mut [u8]) {
+        let flash = (XIP_BASE + offset) as *const u8;
+        buf.copy_from_slice(unsafe { core::slice::from_raw_parts(flash, buf.len()) });
+    }
+
+    fn erase(&mut self, offset: u32) {
+        cortex_m::interrupt::free(|_| unsafe {
+            write(&self.rom, offset, core::ptr::null(), true);
+        });
+    }
+
+    fn program(&mut self, offset: u32, page: &[u8; PAGE_LEN]) {
+        cortex_m::interrupt::free(|_| unsafe {
+            write(&self.rom, offset, page.as_ptr(), false);
+        });
+    }
+}
+
+/// Swaps in an update, or swaps back one that didn't confirm, before anything else runs.
+pub fn boot() {
+    let mut flash = RomFlash::new();
+    if let Boot::Swap(meta) = update::boot(&mut flash) {
+        flash.swap_and_restart(meta);
+    }
+}
+
+/// Keeps the firmware that is under test, if it is.
+pub fn confirm() -> bool {
+    update::confirm(&mut RomFlash::new())
+}
+
+/// The settings stored in the flash.
+#[allow(unused)]
+pub fn settings() -> Settings {
+    Settings::read(&mut RomFlash::new())
+}
+
+/// Stores `settings`, which take effect at the next boot.
+pub fn store_settings(settings: Settings) {
+    settings.write(&mut RomFlash::new());
+}
+
+/// The length of the running firmware, up to the end of the initial values of its variables.
+pub fn active_len() -> u32 {
+    extern "C" {
+        static __sidata: u32;
+        static __sdata: u32;
+        static __edata: u32;
+    }
+    let (init, start, end) = unsafe {
+        (
+            core::ptr::addr_of!(__sidata) as u32,
+            core::ptr::addr_of!(__sdata) as u32,
+            core::ptr::addr_of!(__edata) as u32,
+        )
+    };
+    init + (end - start) - XIP_BASE - ACTIVE
+}
+
+/// Erases the sector at `offset`, or programs `page` at it.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn write(rom: &Rom, offset: u32, page: *const u8, erase: bool) {
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+    if erase {
+        (rom.flash_range_erase)(offset, SECTOR_LEN as usize, BLOCK_LEN, BLOCK_ERASE);
+    } else {
+        (rom.flash_range_program)(offset, page, PAGE_LEN);
+    }
+    (rom.flash_flush_cache)();
+    (rom.boot2)();
+}
+
+/// Swaps the first `len` bytes of the slots, programs `meta` and restarts.
+///
+/// The running firmware is overwritten on the way, so nothing is called but the ROM, the copy of
+/// the second stage bootloader and this function, which are all out of the flash.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn swap_and_restart(rom: &Rom, len: u32, meta: *const u8, a: *mut u32, b: *mut u32) -> ! {
+    let mut offset = 0;
+    while offset < len {
+        (rom.memcpy44)(a, (XIP_BASE + ACTIVE + offset) as *const u32, SECTOR_LEN);
+        (rom.memcpy44)(b, (XIP_BASE + UPDATE + offset) as *const u32, SECTOR_LEN);
+        (rom.connect_internal_flash)();
+        (rom.flash_exit_xip)();
+        (rom.flash_range_erase)(ACTIVE + offset, SECTOR_LEN as usize, BLOCK_LEN, BLOCK_ERASE);
+        (rom.flash_range_program)(ACTIVE + offset, b as *const u8, SECTOR_LEN as usize);
+        (rom.flash_range_erase)(UPDATE + offset, SECTOR_LEN as usize, BLOCK_LEN, BLOCK_ERASE);
+        (rom.flash_range_program)(UPDATE + offset, a as *const u8, SECTOR_LEN as usize);
+        (rom.flash_flush_cache)();
+        (rom.boot2)();
+        offset += SECTOR_LEN;
+    }
+
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+    (rom.flash_range_erase)(META, SECTOR_LEN as usize, BLOCK_LEN, BLOCK_ERASE);
+    (rom.flash_range_program)(META, meta, PAGE_LEN);
+    (rom.flash_flush_cache)();
+    (rom.boot2)();
+
+    #[cfg(target_arch = "arm")]
+    core::arch::asm!(
+        "dsb",
+        "str {value}, [{aircr}]",
+        "dsb",
+        "2:",
+        "b 2b",
+        aircr = in(reg) AIRCR,
+        value = in(reg) SYSRESETREQ,
+        options(noreturn),
+    );
+    #[cfg(not(target_arch = "arm"))]
+    unreachable!("the slots are only swapped on the RP2040")
+}
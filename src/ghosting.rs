@@ -0,0 +1,127 @@
+/// Blocks phantom keys in matrices without (working) diodes.
+///
+/// When three corners of a rectangle in the matrix are pressed, the fourth reads as pressed too.
+/// Whenever all four corners of a rectangle read as pressed, the corners that weren't already
+/// reported are blocked until the rectangle is broken up, since any of them may be the phantom.
+///
+/// The filter takes the debounced state, so that a real corner chattering in the raw scan doesn't
+/// look newly pressed on every bounce.
+pub struct GhostFilter<const COLS: usize, const ROWS: usize> {
+    enabled: bool,
+    reported: [[bool; COLS]; ROWS],
+}
+
+impl<const COLS: usize, const ROWS: usize> GhostFilter<COLS, ROWS> {
+    /// A disabled filter passes every scan through as is, for boards with diodes.
+    pub fn new(enabled: bool) -> Self {
+        GhostFilter {
+            enabled,
+            reported: [[false; COLS]; ROWS],
+        }
+    }
+
+    pub fn filter(&mut self, raw: &[[bool; COLS]; ROWS]) -> [[bool; COLS]; ROWS] {
+        if !self.enabled {
+            return *raw;
+        }
+
+        let mut filtered = *raw;
+        for r1 in 0..ROWS {
+            for r2 in r1 + 1..ROWS {
+                for c1 in 0..COLS {
+                    if !(raw[r1][c1] && raw[r2][c1]) {
+                        continue;
+                    }
+                    for c2 in c1 + 1..COLS {
+                        if !(raw[r1][c2] && raw[r2][c2]) {
+                            continue;
+                        }
+                        for (r, c) in [(r1, c1), (r1, c2), (r2, c1), (r2, c2)] {
+                            if !self.reported[r][c] {
+                                filtered[r][c] = false;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        self.reported = filtered;
+        filtered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debounce::{Algorithm, Debouncer};
+
+    #[test]
+    fn disabled_passes_through() {
+        let mut filter = GhostFilter::<2, 2>::new(false);
+        let state = [[true, true], [true, true]];
+        assert_eq!(filter.filter(&state), state);
+    }
+
+    #[test]
+    fn blocks_fourth_corner() {
+        let mut filter = GhostFilter::<3, 3>::new(true);
+        let three = [
+            [true, false, true],
+            [false, false, false],
+            [true, false, false],
+        ];
+        assert_eq!(filter.filter(&three), three);
+
+        // The phantom key at (2, 2) appears once the three others are pressed.
+        let ghosted = [
+            [true, false, true],
+            [false, false, false],
+            [true, false, true],
+        ];
+        assert_eq!(filter.filter(&ghosted), three);
+        assert_eq!(filter.filter(&ghosted), three);
+
+        // Releasing a corner breaks up the rectangle, so (2, 2) is real.
+        let released = [
+            [false, false, true],
+            [false, false, false],
+            [true, false, true],
+        ];
+        assert_eq!(filter.filter(&released), released);
+    }
+
+    #[test]
+    fn blocks_all_new_corners() {
+        let mut filter = GhostFilter::<2, 2>::new(true);
+        assert_eq!(
+            filter.filter(&[[true, false], [false, false]]),
+            [[true, false], [false, false]]
+        );
+        assert_eq!(
+            filter.filter(&[[true, true], [true, true]]),
+            [[true, false], [false, false]]
+        );
+    }
+
+    #[test]
+    fn unrelated_keys_are_kept() {
+        let mut filter = GhostFilter::<3, 2>::new(true);
+        let state = [[true, true, false], [false, true, true]];
+        assert_eq!(filter.filter(&state), state);
+    }
+
+    #[test]
+    fn debounced_chatter_keeps_real_corners() {
+        let mut debouncer = Debouncer::<2, 2>::new(Algorithm::EagerPress { window_us: 5_000 });
+        let mut filter = GhostFilter::<2, 2>::new(true);
+        let three = [[true, true], [true, false]];
+        let ghosted = [[true, true], [true, true]];
+        assert_eq!(filter.filter(&debouncer.update(&three, 0)), three);
+        assert_eq!(filter.filter(&debouncer.update(&ghosted, 1_000)), three);
+
+        // (0, 1) bounces, and the phantom goes with it for a moment.
+        let bounced = [[true, false], [true, false]];
+        assert_eq!(filter.filter(&debouncer.update(&bounced, 2_000)), three);
+        assert_eq!(filter.filter(&debouncer.update(&ghosted, 2_500)), three);
+    }
+}
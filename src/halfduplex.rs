@@ -0,0 +1,234 @@
+//! Half-duplex serial over a single wire.
+//!
+//! The halves take turns on the wire. Whoever has the turn sends what it has queued followed by a
+//! break, which hands the turn to the other half. The master starts with the turn and takes it
+//! back if the slave doesn't return it in time, e.g. because a break was lost.
+
+use heapless::Deque;
+use rp_pico::hal::Timer;
+
+use crate::{role::Role, transport::Transport};
+
+/// Bytes that can wait to be sent or read.
+const QUEUE_CAPACITY: usize = 256;
+/// How long the master waits for the turn to come back before taking it.
+const TURN_TIMEOUT_US: u64 = 5_000;
+/// How long to wait for the echo of a symbol before giving up on it.
+const ECHO_TIMEOUT_US: u64 = 1_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Symbol {
+    Byte(u8),
+    /// The line held low for longer than a byte.
+    Break,
+}
+
+/// A single wire that both halves can drive.
+pub trait Wire {
+    /// Queues a symbol for sending. Returns `false` if the queue is full.
+    fn send(&mut self, symbol: Symbol) -> bool;
+
+    /// Returns the next symbol heard on the wire, including those sent by this half.
+    fn receive(&mut self) -> Option<Symbol>;
+}
+
+pub trait Clock {
+    fn now_us(&self) -> u64;
+}
+
+impl Clock for &Timer {
+    fn now_us(&self) -> u64 {
+        self.get_counter().ticks()
+    }
+}
+
+/// A [`Transport`] that takes turns on a [`Wire`].
+///
+/// The turns are taken whenever the transport is read from or written to, so it has to be polled
+/// regularly, which the split link does anyway.
+pub struct HalfDuplex<W, C> {
+    wire: W,
+    clock: C,
+    role: Role,
+    rx: Deque<u8, QUEUE_CAPACITY>,
+    tx: Deque<u8, QUEUE_CAPACITY>,
+    has_turn: bool,
+    /// Symbols sent by this half that haven't been heard back yet.
+    echoes: usize,
+    t_last_sent: u64,
+    /// Bytes that were dropped because a queue was full.
+    pub overruns: u32,
+}
+
+impl<W: Wire, C: Clock> HalfDuplex<W, C> {
+    pub fn new(wire: W, clock: C, role: Role) -> Self {
+        HalfDuplex {
+            wire,
+            clock,
+            role,
+            rx: Deque::new(),
+            tx: Deque::new(),
+            has_turn: role == Role::Master,
+            echoes: 0,
+            t_last_sent: 0,
+            overruns: 0,
+        }
+    }
+
+    fn receive(&mut self) {
+        while let Some(symbol) = self.wire.receive() {
+            if self.echoes > 0 {
+                self.echoes -= 1;
+                continue;
+            }
+            match symbol {
+                Symbol::Byte(byte) => {
+                    if self.rx.push_back(byte).is_err() {
+                        self.overruns += 1;
+                    }
+                }
+                Symbol::Break => self.has_turn = true,
+            }
+        }
+    }
+
+    fn send(&mut self, symbol: Symbol) {
+        // The echoes have to be taken out while waiting, or they would overflow the wire's
+        // receive queue.
+        while !self.wire.send(symbol) {
+            self.receive();
+        }
+        self.echoes += 1;
+        self.t_last_sent = self.clock.now_us();
+    }
+
+    /// Receives what's on the wire, and takes the turn if it's ours.
+    fn service(&mut self) {
+        self.receive();
+
+        let now = self.clock.now_us();
+        if self.echoes > 0 && now - self.t_last_sent > ECHO_TIMEOUT_US {
+            // An echo was lost, so nothing else from this turn will be heard back.
+            self.echoes = 0;
+        }
+        if self.echoes > 0 {
+            // The last turn is still on the wire.
+            return;
+        }
+        if !self.has_turn && self.role == Role::Master && now - self.t_last_sent > TURN_TIMEOUT_US {
+            self.has_turn = true;
+        }
+        if self.has_turn {
+            // The turn is passed on even when there is nothing to send, so that the other half
+            // can always talk.
+            while let Some(byte) = self.tx.pop_front() {
+                self.send(Symbol::Byte(byte));
+            }
+            self.send(Symbol::Break);
+            self.has_turn = false;
+        }
+    }
+}
+
+impl<W: Wire, C: Clock> Transport for HalfDuplex<W, C> {
+    fn read(&mut self) -> Option<u8> {
+        if self.rx.is_empty() {
+            self.service();
+        }
+        self.rx.pop_front()
+    }
+
+    /// Queues `bytes` for the next turn.
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if self.tx.push_back(byte).is_err() {
+                self.overruns += 1;
+            }
+        }
+        self.service();
+    }
+
+    fn is_readable(&self) -> bool {
+        !self.rx.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        comms::{ComLink, SlaveLink, Update},
+        mock::{MockClock, MockWire},
+        protocol::{Hello, Message, Peer},
+    };
+
+    fn pair() -> (
+        HalfDuplex<MockWire, MockClock>,
+        HalfDuplex<MockWire, MockClock>,
+        MockWire,
+        MockClock,
+    ) {
+        let clock = MockClock::new();
+        let (a, b) = MockWire::pair();
+        let master = HalfDuplex::new(a.clone(), clock.clone(), Role::Master);
+        let slave = HalfDuplex::new(b, clock.clone(), Role::Slave);
+        (master, slave, a, clock)
+    }
+
+    fn read_all(end: &mut impl Transport) -> std::vec::Vec<u8> {
+        core::iter::from_fn(|| end.read()).collect()
+    }
+
+    #[test]
+    fn halves_take_turns() {
+        let (mut master, mut slave, wire, _) = pair();
+        // The slave waits for its turn.
+        slave.write(&[3]);
+        assert_eq!(wire.symbols(), []);
+        master.write(&[1, 2]);
+        assert_eq!(
+            wire.symbols(),
+            [Symbol::Byte(1), Symbol::Byte(2), Symbol::Break]
+        );
+
+        assert_eq!(read_all(&mut slave), [1, 2]);
+        assert_eq!(read_all(&mut master), [3]);
+        assert_eq!(read_all(&mut slave), []);
+        assert_eq!(master.overruns + slave.overruns, 0);
+    }
+
+    #[test]
+    fn master_takes_back_lost_turn() {
+        let (mut master, mut slave, wire, clock) = pair();
+        wire.lose_next_break();
+        master.write(&[1]);
+        assert_eq!(read_all(&mut slave), [1]);
+
+        // The slave never got the turn, so its bytes wait.
+        slave.write(&[2]);
+        clock.advance(TURN_TIMEOUT_US / 2);
+        assert_eq!(read_all(&mut master), []);
+        clock.advance(TURN_TIMEOUT_US);
+        assert_eq!(read_all(&mut master), []);
+        assert_eq!(read_all(&mut slave), []);
+        assert_eq!(read_all(&mut master), [2]);
+    }
+
+    #[test]
+    fn carries_the_split_link() {
+        let (mut master_end, mut slave_end, _, clock) = pair();
+        let mut master = ComLink::<1>::new(Hello::new(Peer::Master, 2, 2));
+        let mut slave = SlaveLink::new(Hello::new(Peer::Slave, 2, 2));
+        master.send(Message::Layer(3));
+
+        let (mut updates, mut messages) = (std::vec::Vec::new(), std::vec::Vec::new());
+        for _ in 0..2_000 {
+            clock.advance(100);
+            let now = clock.now_us();
+            messages.extend(slave.poll(&mut slave_end, now, &[0b101]));
+            updates.extend(master.poll(&mut master_end, now));
+        }
+        assert_eq!(messages, [Message::Layer(3)]);
+        assert_eq!(updates[0], Update::State([0b101]));
+    }
+}
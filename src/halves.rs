@@ -0,0 +1,126 @@
+//! The wiring of each physical half, and where its keys go in the keymap.
+//!
+//! Either half can be the master: the keymap is always laid out from the left half to the right
+//! half, and each half places its own matrix into it.
+
+use crate::layout;
+
+/// The size of the matrix of each half.
+pub const ROWS: usize = 5;
+pub const COLS: usize = 6;
+
+/// The keys of both halves, as seen by the keymap.
+pub type Grid = [[bool; layout::COLS]; layout::ROWS];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hand {
+    Left,
+    Right,
+}
+
+/// How a half is wired, and how its matrix maps onto the keymap.
+pub struct Half {
+    /// GPIO numbers of the rows, from the top.
+    pub rows: [u8; ROWS],
+    /// GPIO numbers of the columns, from the inner edge.
+    pub cols: [u8; COLS],
+    /// GPIO numbers of the TX and RX pins of the split link. The single wire is the TX pin.
+    pub link: (u8, u8),
+    /// GPIO numbers of the TX and RX pins of the bus of the modules, on UART1.
+    #[allow(unused)]
+    pub modules: (u8, u8),
+    /// The keymap column of the outermost key of the half.
+    pub col_offset: usize,
+    /// Whether the matrix columns run from right to left in the keymap.
+    pub mirrored: bool,
+}
+
+const LEFT: Half = Half {
+    rows: [8, 10, 15, 13, 12],
+    cols: [7, 6, 22, 26, 2, 0],
+    link: (16, 17),
+    modules: (4, 5),
+    col_offset: 0,
+    mirrored: true,
+};
+
+const RIGHT: Half = Half {
+    rows: [20, 19, 18, 17, 16],
+    cols: [21, 22, 10, 11, 7, 9],
+    link: (12, 13),
+    modules: (4, 5),
+    col_offset: COLS,
+    mirrored: false,
+};
+
+impl Hand {
+    pub fn half(self) -> &'static Half {
+        match self {
+            Hand::Left => &LEFT,
+            Hand::Right => &RIGHT,
+        }
+    }
+
+    /// The hand of the other half.
+    pub fn other(self) -> Hand {
+        match self {
+            Hand::Left => Hand::Right,
+            Hand::Right => Hand::Left,
+        }
+    }
+}
+
+impl Half {
+    /// The keymap position of the key at `(ri, ci)` of the matrix.
+    pub fn position(&self, ri: usize, ci: usize) -> (usize, usize) {
+        let ci = if self.mirrored { COLS - 1 - ci } else { ci };
+        (ri, self.col_offset + ci)
+    }
+
+    /// Copies the state of the matrix into the keymap grid.
+    pub fn place(&self, pressed: &[[bool; COLS]; ROWS], grid: &mut Grid) {
+        for (ri, row) in pressed.iter().enumerate() {
+            for (ci, &key) in row.iter().enumerate() {
+                let (r, c) = self.position(ri, ci);
+                grid[r][c] = key;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn halves_fill_the_keymap() {
+        let mut seen = [[0; layout::COLS]; layout::ROWS];
+        for hand in [Hand::Left, Hand::Right] {
+            for ri in 0..ROWS {
+                for ci in 0..COLS {
+                    let (r, c) = hand.half().position(ri, ci);
+                    seen[r][c] += 1;
+                }
+            }
+        }
+        assert_eq!(seen[..ROWS], [[1; layout::COLS]; ROWS]);
+    }
+
+    #[test]
+    fn inner_columns_meet_in_the_middle() {
+        assert_eq!(Hand::Left.half().position(0, 0), (0, COLS - 1));
+        assert_eq!(Hand::Right.half().position(0, 0), (0, COLS));
+        assert_eq!(Hand::Right.half().position(4, 5), (4, 2 * COLS - 1));
+    }
+
+    #[test]
+    fn place_moves_keys() {
+        let mut pressed = [[false; COLS]; ROWS];
+        pressed[2][1] = true;
+        let mut grid = [[false; layout::COLS]; layout::ROWS];
+        Hand::Left.half().place(&pressed, &mut grid);
+        assert!(grid[2][COLS - 2]);
+        Hand::Left.half().place(&[[false; COLS]; ROWS], &mut grid);
+        assert_eq!(grid, [[false; layout::COLS]; layout::ROWS]);
+    }
+}
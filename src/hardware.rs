@@ -1,37 +1,72 @@
 #![warn(unsafe_op_in_unsafe_fn)]
 
+#[cfg(not(test))]
 use core::panic::PanicInfo;
 
-use embedded_alloc::Heap;
-use rp_pico::{entry, hal::rom_data::reset_to_usb_boot};
+use rp_pico::{hal::gpio::DynPin, Pins};
 
-// The rheap allocator.
-#[global_allocator]
-static HEAP: Heap = Heap::empty();
+#[cfg(not(test))]
+use rp_pico::{entry, hal::rom_data::reset_to_usb_boot};
 
 /// The entry point. Sets up the hardware.
+#[cfg(not(test))]
 #[entry]
 fn entry() -> ! {
-    unsafe { init_heap() };
-
     super::start()
 }
 
-/// Initializes the heap using the symbols provided by memory.x.
-pub unsafe fn init_heap() {
-    extern "C" {
-        static mut _heap_start: u32;
-        static mut _heap_end: u32;
-    }
+/// The input levels of GPIO bank 0, read through the SIO.
+pub struct SioBank;
 
-    unsafe {
-        let start = &mut _heap_start as *mut u32 as usize;
-        let end = &mut _heap_end as *mut u32 as usize;
-        assert!(end > start);
-        HEAP.init(start, end - start);
+impl crate::buttonmatrix::InputBank for SioBank {
+    fn read(&mut self) -> u32 {
+        // SAFETY: Reading the input levels has no side effects.
+        unsafe { (*rp_pico::pac::SIO::ptr()).gpio_in.read().bits() }
     }
 }
 
+/// The GPIO of the Pico's LED.
+pub const LED: u8 = 25;
+
+/// Takes every pin of the bank, indexed by GPIO number, so that pins can be picked from lists of
+/// numbers.
+pub fn pins_by_number(pins: Pins) -> [Option<DynPin>; 30] {
+    [
+        pins.gpio0.into(),
+        pins.gpio1.into(),
+        pins.gpio2.into(),
+        pins.gpio3.into(),
+        pins.gpio4.into(),
+        pins.gpio5.into(),
+        pins.gpio6.into(),
+        pins.gpio7.into(),
+        pins.gpio8.into(),
+        pins.gpio9.into(),
+        pins.gpio10.into(),
+        pins.gpio11.into(),
+        pins.gpio12.into(),
+        pins.gpio13.into(),
+        pins.gpio14.into(),
+        pins.gpio15.into(),
+        pins.gpio16.into(),
+        pins.gpio17.into(),
+        pins.gpio18.into(),
+        pins.gpio19.into(),
+        pins.gpio20.into(),
+        pins.gpio21.into(),
+        pins.gpio22.into(),
+        pins.b_power_save.into(),
+        pins.vbus_detect.into(),
+        pins.led.into(),
+        pins.gpio26.into(),
+        pins.gpio27.into(),
+        pins.gpio28.into(),
+        pins.voltage_monitor.into(),
+    ]
+    .map(|pin: DynPin| Some(pin))
+}
+
+#[cfg(not(test))]
 #[panic_handler]
 fn panic_handler(_info: &PanicInfo) -> ! {
     loop {
@@ -44,8 +79,7 @@ pub mod serial {
     use core::fmt::{Arguments, Write};
     use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
-    use alloc::string::String;
-    use alloc::vec::Vec;
+    use heapless::String;
     use rp_pico::hal::clocks::UsbClock;
     use rp_pico::hal::usb::UsbBus;
     use rp_pico::pac::{interrupt, Interrupt, NVIC, RESETS, USBCTRL_DPRAM, USBCTRL_REGS};
@@ -186,27 +220,45 @@ pub mod serial {
 
     /// Reads until the specified byte is found. Returns the results as a `String`.
     /// The byte is included in the string. Invalid characters are replaced with the
-    /// replacement character (U+FFFD).
+    /// replacement character (U+FFFD). Anything that doesn't fit into the string is discarded.
     #[allow(unused)]
-    pub fn read_until(byte: u8) -> String {
-        let mut buf = Vec::new();
+    pub fn read_until<const N: usize>(byte: u8) -> String<N> {
+        let mut buf = heapless::Vec::<u8, N>::new();
         let mut terminated = false;
 
         while !terminated {
             read(|data| {
-                if let Some(i) = data.iter().position(|&b| b == byte) {
-                    terminated = true;
-                    buf.extend_from_slice(&data[..=i]);
-                    i + 1
-                } else {
-                    buf.extend_from_slice(data);
-                    data.len()
-                }
+                let (consumed, line) = match data.iter().position(|&b| b == byte) {
+                    Some(i) => {
+                        terminated = true;
+                        (i + 1, &data[..=i])
+                    }
+                    None => (data.len(), data),
+                };
+                let free = N - buf.len();
+                _ = buf.extend_from_slice(&line[..line.len().min(free)]);
+                consumed
             })
         }
 
-        String::from_utf8(buf)
-            .unwrap_or_else(|err| String::from_utf8_lossy(err.as_bytes()).into_owned())
+        let mut string = String::new();
+        let mut bytes = &buf[..];
+        while !bytes.is_empty() {
+            match core::str::from_utf8(bytes) {
+                Ok(valid) => {
+                    _ = string.push_str(valid);
+                    break;
+                }
+                Err(err) => {
+                    let (valid, rest) = bytes.split_at(err.valid_up_to());
+                    // SAFETY: `valid_up_to` guarantees that the prefix is valid UTF-8.
+                    _ = string.push_str(unsafe { core::str::from_utf8_unchecked(valid) });
+                    _ = string.push(char::REPLACEMENT_CHARACTER);
+                    bytes = &rest[err.error_len().unwrap_or(rest.len())..];
+                }
+            }
+        }
+        string
     }
 
     /// Writes the data to the serial device.
@@ -0,0 +1,122 @@
+//! The split link on UART0, whose interrupt moves bytes between the FIFOs and ring buffers.
+//!
+//! Received bytes wait in the ring buffer instead of overflowing the 32 byte FIFO while the main
+//! loop is busy, and writing returns as soon as the bytes are buffered instead of waiting for them
+//! to be sent.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embedded_hal::serial::Read;
+use heapless::Deque;
+use rp_pico::{
+    hal::uart::{Enabled, UartPeripheral},
+    pac::{interrupt, Interrupt, NVIC, UART0},
+};
+
+use crate::transport::{DynUartPins, Transport};
+
+/// How many received bytes can wait to be read, and how many can wait to be sent.
+const RX_CAPACITY: usize = 512;
+const TX_CAPACITY: usize = 512;
+
+type Uart = UartPeripheral<Enabled, UART0, DynUartPins<UART0>>;
+
+static mut UART: Option<Uart> = None;
+static mut RX: Deque<u8, RX_CAPACITY> = Deque::new();
+static mut TX: Deque<u8, TX_CAPACITY> = Deque::new();
+/// Received bytes dropped because the ring buffer was full.
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// The split link's end of UART0.
+pub struct IrqUart {
+    _private: (),
+}
+
+impl IrqUart {
+    /// Moves `uart` to its interrupt.
+    ///
+    /// # Safety
+    ///
+    /// May only be called once.
+    pub unsafe fn new(mut uart: Uart) -> Self {
+        uart.enable_rx_interrupt();
+        cortex_m::interrupt::free(|_| unsafe { UART = Some(uart) });
+        unsafe { NVIC::unmask(Interrupt::UART0_IRQ) };
+        IrqUart { _private: () }
+    }
+
+    /// Received bytes dropped because the main loop didn't read them in time.
+    ///
+    /// Bytes overrun in the FIFO aren't counted. That happens on the slave while it erases the
+    /// flash during an update, with interrupts off for tens of milliseconds, and the link
+    /// retransmits what was lost.
+    pub fn dropped(&self) -> u32 {
+        DROPPED.load(Ordering::Relaxed)
+    }
+}
+
+impl Transport for IrqUart {
+    fn read(&mut self) -> Option<u8> {
+        cortex_m::interrupt::free(|_| unsafe { RX.pop_front() })
+    }
+
+    /// Buffers `bytes` for the interrupt to send, only blocking while the buffer is full.
+    fn write(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            bytes = cortex_m::interrupt::free(|_| {
+                let tx = unsafe { &mut TX };
+                let (now, later) = bytes.split_at(bytes.len().min(tx.capacity() - tx.len()));
+                for &byte in now {
+                    _ = tx.push_back(byte);
+                }
+                if let Some(uart) = unsafe { UART.as_mut() } {
+                    send(uart, tx);
+                }
+                later
+            });
+        }
+    }
+
+    fn is_readable(&self) -> bool {
+        cortex_m::interrupt::free(|_| unsafe { !RX.is_empty() })
+    }
+}
+
+/// Fills the FIFO from the buffer, and keeps the interrupt on while bytes are left to send.
+///
+/// The interrupt only fires when the FIFO drains below its threshold, so it has to be filled
+/// before the interrupt takes over.
+fn send(uart: &mut Uart, tx: &mut Deque<u8, TX_CAPACITY>) {
+    while uart.uart_is_writable() {
+        let Some(byte) = tx.pop_front() else {
+            break;
+        };
+        _ = uart.write_raw(&[byte]);
+    }
+    if tx.is_empty() {
+        uart.disable_tx_interrupt();
+    } else {
+        uart.enable_tx_interrupt();
+    }
+}
+
+/// UART0 interrupt, when bytes were received or the FIFO has room for more.
+#[interrupt]
+#[allow(non_snake_case)]
+unsafe fn UART0_IRQ() {
+    let Some(uart) = (unsafe { UART.as_mut() }) else {
+        return;
+    };
+    let rx = unsafe { &mut RX };
+    while uart.uart_is_readable() {
+        // Bytes with framing or parity errors are dropped, and the frame they belong to is
+        // rejected by its CRC.
+        if let Ok(byte) = Read::read(uart) {
+            if rx.push_back(byte).is_err() {
+                // Only this interrupt counts, so the count can't change in between.
+                DROPPED.store(DROPPED.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+            }
+        }
+    }
+    send(uart, unsafe { &mut TX });
+}
@@ -1,12 +1,30 @@
-use alloc::vec::Vec;
-use rp_pico::hal::{timer::Instant, Timer};
-use smallvec::SmallVec;
+use heapless::Vec;
+use rp_pico::hal::timer::Instant;
 use usbd_human_interface_device::page::Keyboard;
 
-use self::layout::LAYOUT;
+use self::layout::{LAYOUT, MIRROR, STANDALONE};
 
-const ROWS: usize = 5;
-const COLS: usize = 12;
+/// The rows of the halves, followed by the rows of the modules.
+#[cfg(not(feature = "modules"))]
+pub const ROWS: usize = 5;
+#[cfg(feature = "modules")]
+pub const ROWS: usize = 10;
+pub const COLS: usize = 12;
+
+/// Maximum number of keys in a single report.
+pub const REPORT_CAPACITY: usize = 16;
+/// Maximum number of held modifiers sent along with every report.
+pub const HOLDS_CAPACITY: usize = 8;
+/// Maximum number of reports produced by a single update.
+pub const ACTIONS_CAPACITY: usize = 8;
+
+pub type Report = Vec<Keyboard, REPORT_CAPACITY>;
+pub type Holds = Vec<Keyboard, HOLDS_CAPACITY>;
+pub type Actions = Vec<Report, ACTIONS_CAPACITY>;
+/// Layers of keys, each covering both halves.
+pub type Keymap = [[[Key; COLS]; ROWS]];
+/// When each key last changed state, by the clock of the master.
+pub type Times = [[Instant; COLS]; ROWS];
 
 #[derive(Clone, Copy, PartialEq)]
 pub enum Key {
@@ -17,13 +35,19 @@ pub enum Key {
     Hold(Keyboard),
     Drop,
     Empty,
+    /// Mirrors the keys of both halves onto each other while held.
+    SwapHands,
+    /// Mirrors the keys of both halves onto each other until pressed again.
+    SwapHandsToggle,
 }
 
+#[allow(clippy::module_inception)]
 mod layout {
     #![allow(non_upper_case_globals)]
 
     use super::Key::Press as PR;
-    use super::Key::{self, Drop, Empty, Hold, LayerChange, OnClick};
+    use super::Key::{self, Drop, Empty, Hold, LayerChange, OnClick, SwapHands, SwapHandsToggle};
+    use super::ROWS;
     // use usbd_human_interface_edvice::page::Keyboard;
     use super::Key::Combo as CB;
     use usbd_human_interface_device::page::Keyboard::LeftShift as LS;
@@ -45,14 +69,14 @@ mod layout {
     const Bar: Key = CB(LS, Backslash);
 
     #[rustfmt::skip]
-    pub const LAYOUT: [[[Key; 12]; 5]; 4] = [
+    const LAYOUT_HALVES: [[[Key; 12]; 5]; 4] = [
         [
-            [ Empty, PR(Q), PR(W), PR(F), PR(P), PR(G), PR(J), PR(L), PR(U), PR(Y), PR(Semicolon), PR(DeleteBackspace), ],
+            [ SwapHandsToggle, PR(Q), PR(W), PR(F), PR(P), PR(G), PR(J), PR(L), PR(U), PR(Y), PR(Semicolon), PR(DeleteBackspace), ],
             [ OnClick(Escape, LeftShift, 150), PR(A), PR(R), PR(S), PR(T), PR(D), PR(H), PR(N), PR(E), PR(I), PR(O), PR(Apostrophe), ],
             [ Hold(LeftControl), PR(Z), PR(X), PR(C), PR(V), PR(B), PR(K), PR(M), PR(Comma), PR(Dot), PR(ForwardSlash), PR(ReturnEnter), ],
             [ Empty, Empty, Empty, Empty, LayerChange(2), PR(Space), Hold(RightShift), LayerChange(1), Empty, Empty, Empty, Empty, ],
             [ Empty, Empty, Empty, Empty, Empty, Hold(LeftGUI), Hold(LeftAlt), Empty, Empty, Empty, Empty, Empty, ],
-        ],        
+        ],
         [
             [ Drop, At, Percent, PR(Grave), Octohorp, PR(LeftBrace), PR(RightBrace), PR(Keyboard7), PR(Keyboard8), PR(Keyboard9), PR(KeypadAdd), Drop],
             [ Bar, Underscore, Ampersand, Mul, PR(Equal), LeftPar, RightPar, PR(Keyboard4), PR(Keyboard5), PR(Keyboard6), PR(Keyboard0), Drop,],
@@ -60,15 +84,13 @@ mod layout {
             [ Empty, Empty, Empty, Empty, Drop, Drop, Drop, Drop, Empty, Empty, Empty, Empty, ],
             [ Empty, Empty, Empty, Empty, Empty, Drop, Drop, Empty, Empty, Empty, Empty, Empty, ],
         ],
-
         [
-            [ Drop, PR(F1), PR(F2), PR(F3), PR(F4), PR(F5), PR(F10), PR(F11), PR(F12), PR(PrintScreen), Empty, Drop, ],
+            [ SwapHands, PR(F1), PR(F2), PR(F3), PR(F4), PR(F5), PR(F10), PR(F11), PR(F12), PR(PrintScreen), Empty, Drop, ],
             [ Drop, CB(Q, RightAlt), CB(W, RightAlt), CB(P, RightAlt), Hold(RightShift), PR(F6), PR(F9), PR(LeftArrow), PR(DownArrow), PR(UpArrow), PR(RightArrow), Drop, ],
             [ Drop, Empty, CB(LeftControl, Tab), PR(Tab), Empty, PR(F7), PR(F8), PR(Home), PR(PageDown), PR(PageUp), PR(End), Drop, ],
             [ Drop, Empty, Empty, Empty, Drop, Drop, Drop, Drop, Empty, Empty, Empty, Empty, ],
             [ Empty, Empty, Empty, Empty, Empty, Drop, Drop, Empty, Empty, Empty, Empty, Empty, ],
         ],
-
         [
             [ Drop, PR(F1), PR(F2), PR(F3), PR(F4), PR(F5), PR(F10), PR(F11), PR(F12), PR(PrintScreen), Empty, Drop, ],
             [ Drop, CB(Q, RightAlt), CB(W, RightAlt), CB(P, RightAlt), PR(RightShift), PR(F6), PR(F9), PR(LeftArrow), PR(DownArrow), PR(UpArrow), PR(RightArrow), Drop, ],
@@ -76,8 +98,122 @@ mod layout {
             [ Drop, Empty, Empty, Empty, Drop, Drop, Drop, Drop, Empty, Empty, Empty, Empty, ],
             [ Empty, Empty, Empty, Empty, Empty, Drop, Drop, Empty, Empty, Empty, Empty, Empty, ],
         ],
-        
     ];
+
+    /// The numpad, and the thumb cluster next to it.
+    #[cfg(feature = "modules")]
+    #[rustfmt::skip]
+    const LAYOUT_MODULES: [[[Key; 12]; 5]; 4] = [
+        [
+            [ PR(KeypadNumLockAndClear), PR(KeypadDivide), PR(KeypadMultiply), PR(KeypadSubtract), PR(DeleteBackspace), PR(ReturnEnter), PR(Tab), Empty, Empty, Empty, Empty, Empty, ],
+            [ PR(Keypad7), PR(Keypad8), PR(Keypad9), PR(KeypadAdd), Hold(LeftControl), Hold(LeftAlt), Hold(LeftGUI), Empty, Empty, Empty, Empty, Empty, ],
+            [ PR(Keypad4), PR(Keypad5), PR(Keypad6), PR(KeypadAdd), Empty, Empty, Empty, Empty, Empty, Empty, Empty, Empty, ],
+            [ PR(Keypad1), PR(Keypad2), PR(Keypad3), PR(KeypadEnter), Empty, Empty, Empty, Empty, Empty, Empty, Empty, Empty, ],
+            [ PR(Keypad0), PR(Keypad0), PR(KeypadDot), PR(KeypadEnter), Empty, Empty, Empty, Empty, Empty, Empty, Empty, Empty, ],
+        ],
+        [
+            [ Drop, Drop, Drop, Drop, Drop, Drop, Drop, Empty, Empty, Empty, Empty, Empty, ],
+            [ Drop, Drop, Drop, Drop, Drop, Drop, Drop, Empty, Empty, Empty, Empty, Empty, ],
+            [ Drop, Drop, Drop, Drop, Empty, Empty, Empty, Empty, Empty, Empty, Empty, Empty, ],
+            [ Drop, Drop, Drop, Drop, Empty, Empty, Empty, Empty, Empty, Empty, Empty, Empty, ],
+            [ Drop, Drop, Drop, Drop, Empty, Empty, Empty, Empty, Empty, Empty, Empty, Empty, ],
+        ],
+        [
+            [ Drop, Drop, Drop, Drop, Drop, Drop, Drop, Empty, Empty, Empty, Empty, Empty, ],
+            [ Drop, Drop, Drop, Drop, Drop, Drop, Drop, Empty, Empty, Empty, Empty, Empty, ],
+            [ Drop, Drop, Drop, Drop, Empty, Empty, Empty, Empty, Empty, Empty, Empty, Empty, ],
+            [ Drop, Drop, Drop, Drop, Empty, Empty, Empty, Empty, Empty, Empty, Empty, Empty, ],
+            [ Drop, Drop, Drop, Drop, Empty, Empty, Empty, Empty, Empty, Empty, Empty, Empty, ],
+        ],
+        [
+            [ Drop, Drop, Drop, Drop, Drop, Drop, Drop, Empty, Empty, Empty, Empty, Empty, ],
+            [ Drop, Drop, Drop, Drop, Drop, Drop, Drop, Empty, Empty, Empty, Empty, Empty, ],
+            [ Drop, Drop, Drop, Drop, Empty, Empty, Empty, Empty, Empty, Empty, Empty, Empty, ],
+            [ Drop, Drop, Drop, Drop, Empty, Empty, Empty, Empty, Empty, Empty, Empty, Empty, ],
+            [ Drop, Drop, Drop, Drop, Empty, Empty, Empty, Empty, Empty, Empty, Empty, Empty, ],
+        ],
+    ];
+
+    /// The rows of the halves, with the rows of the modules below them if there are modules.
+    #[cfg(not(feature = "modules"))]
+    pub const LAYOUT: [[[Key; 12]; ROWS]; 4] = LAYOUT_HALVES;
+    #[cfg(feature = "modules")]
+    pub const LAYOUT: [[[Key; 12]; ROWS]; 4] = stack(LAYOUT_HALVES, LAYOUT_MODULES);
+
+    /// The layers used while the other half is missing. Holding the inner thumb key mirrors the
+    /// other half onto this one, so every letter can be typed with one hand.
+    #[rustfmt::skip]
+    const STANDALONE_HALVES: [[[Key; 12]; 5]; 2] = [
+        [
+            [ Empty, PR(Q), PR(W), PR(F), PR(P), PR(G), PR(J), PR(L), PR(U), PR(Y), PR(Semicolon), PR(DeleteBackspace), ],
+            [ OnClick(Escape, LeftShift, 150), PR(A), PR(R), PR(S), PR(T), PR(D), PR(H), PR(N), PR(E), PR(I), PR(O), PR(Apostrophe), ],
+            [ Hold(LeftControl), PR(Z), PR(X), PR(C), PR(V), PR(B), PR(K), PR(M), PR(Comma), PR(Dot), PR(ForwardSlash), PR(ReturnEnter), ],
+            [ Empty, Empty, Empty, Empty, LayerChange(1), PR(Space), Hold(RightShift), LayerChange(1), Empty, Empty, Empty, Empty, ],
+            [ Empty, Empty, Empty, Empty, Empty, Hold(LeftGUI), Hold(LeftAlt), Empty, Empty, Empty, Empty, Empty, ],
+        ],
+        [
+            [ PR(DeleteBackspace), PR(Semicolon), PR(Y), PR(U), PR(L), PR(J), PR(G), PR(P), PR(F), PR(W), PR(Q), Drop, ],
+            [ PR(Apostrophe), PR(O), PR(I), PR(E), PR(N), PR(H), PR(D), PR(T), PR(S), PR(R), PR(A), Drop, ],
+            [ PR(ReturnEnter), PR(ForwardSlash), PR(Dot), PR(Comma), PR(M), PR(K), PR(B), PR(V), PR(C), PR(X), PR(Z), Drop, ],
+            [ Empty, Empty, Empty, Empty, Drop, Drop, Drop, Drop, Empty, Empty, Empty, Empty, ],
+            [ Empty, Empty, Empty, Empty, Empty, Drop, Drop, Empty, Empty, Empty, Empty, Empty, ],
+        ],
+    ];
+
+    #[cfg(feature = "modules")]
+    #[rustfmt::skip]
+    const STANDALONE_MODULES: [[[Key; 12]; 5]; 2] = [
+        [
+            [ PR(KeypadNumLockAndClear), PR(KeypadDivide), PR(KeypadMultiply), PR(KeypadSubtract), PR(DeleteBackspace), PR(ReturnEnter), PR(Tab), Empty, Empty, Empty, Empty, Empty, ],
+            [ PR(Keypad7), PR(Keypad8), PR(Keypad9), PR(KeypadAdd), Hold(LeftControl), Hold(LeftAlt), Hold(LeftGUI), Empty, Empty, Empty, Empty, Empty, ],
+            [ PR(Keypad4), PR(Keypad5), PR(Keypad6), PR(KeypadAdd), Empty, Empty, Empty, Empty, Empty, Empty, Empty, Empty, ],
+            [ PR(Keypad1), PR(Keypad2), PR(Keypad3), PR(KeypadEnter), Empty, Empty, Empty, Empty, Empty, Empty, Empty, Empty, ],
+            [ PR(Keypad0), PR(Keypad0), PR(KeypadDot), PR(KeypadEnter), Empty, Empty, Empty, Empty, Empty, Empty, Empty, Empty, ],
+        ],
+        [
+            [ Drop, Drop, Drop, Drop, Drop, Drop, Drop, Empty, Empty, Empty, Empty, Empty, ],
+            [ Drop, Drop, Drop, Drop, Drop, Drop, Drop, Empty, Empty, Empty, Empty, Empty, ],
+            [ Drop, Drop, Drop, Drop, Empty, Empty, Empty, Empty, Empty, Empty, Empty, Empty, ],
+            [ Drop, Drop, Drop, Drop, Empty, Empty, Empty, Empty, Empty, Empty, Empty, Empty, ],
+            [ Drop, Drop, Drop, Drop, Empty, Empty, Empty, Empty, Empty, Empty, Empty, Empty, ],
+        ],
+    ];
+
+    #[cfg(not(feature = "modules"))]
+    pub const STANDALONE: [[[Key; 12]; ROWS]; 2] = STANDALONE_HALVES;
+    #[cfg(feature = "modules")]
+    pub const STANDALONE: [[[Key; 12]; ROWS]; 2] = stack(STANDALONE_HALVES, STANDALONE_MODULES);
+
+    /// The column of the mirror of each key of the halves, on the other half. The keys of the
+    /// modules stay where they are.
+    #[rustfmt::skip]
+    pub const MIRROR: [[u8; 12]; 5] = [
+        [ 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, ],
+        [ 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, ],
+        [ 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, ],
+        [ 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, ],
+        [ 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, ],
+    ];
+
+    /// Puts the rows of the modules below the rows of the halves, in every layer.
+    #[cfg(feature = "modules")]
+    const fn stack<const N: usize>(
+        halves: [[[Key; 12]; 5]; N],
+        modules: [[[Key; 12]; 5]; N],
+    ) -> [[[Key; 12]; ROWS]; N] {
+        let mut keymap = [[[Key::Empty; 12]; ROWS]; N];
+        let mut layer = 0;
+        while layer < N {
+            let mut ri = 0;
+            while ri < 5 {
+                keymap[layer][ri] = halves[layer][ri];
+                keymap[layer][ri + 5] = modules[layer][ri];
+                ri += 1;
+            }
+            layer += 1;
+        }
+        keymap
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -85,66 +221,178 @@ pub struct ButtonState {
     pressed: bool,
     t_change: Instant,
     pressed_layer: u8,
+    /// Whether the hands were swapped when the key was pressed.
+    pressed_swapped: bool,
 }
 
 pub struct KeyboardLogic {
     prev_pressed: [[ButtonState; COLS]; ROWS],
     t_last_key_sent: Instant,
+    dropped_keys: u32,
+    layer: u8,
+    keymap: &'static Keymap,
+    swap_toggled: bool,
 }
 
 impl KeyboardLogic {
-    pub fn new(timer: &Timer) -> Self {
-        let t = timer.get_counter();
+    pub fn new(t: Instant) -> Self {
         KeyboardLogic {
             prev_pressed: [[ButtonState {
                 pressed: false,
                 t_change: t,
                 pressed_layer: 0,
+                pressed_swapped: false,
             }; COLS]; ROWS],
             t_last_key_sent: t,
+            dropped_keys: 0,
+            layer: 0,
+            keymap: &LAYOUT,
+            swap_toggled: false,
+        }
+    }
+
+    /// Number of keys that have been dropped because a report, the holds or the actions were full.
+    pub fn dropped_keys(&self) -> u32 {
+        self.dropped_keys
+    }
+
+    /// The layer selected by the layer keys held during the last update.
+    pub fn layer(&self) -> u8 {
+        self.layer
+    }
+
+    /// Whether the standalone layers are used.
+    pub fn is_standalone(&self) -> bool {
+        core::ptr::eq(self.keymap, &STANDALONE[..])
+    }
+
+    /// Switches between the standalone layers and the normal ones.
+    ///
+    /// Keys held while switching do nothing until they are pressed again, like when the layer
+    /// changes.
+    pub fn set_standalone(&mut self, standalone: bool) {
+        if standalone == self.is_standalone() {
+            return;
         }
+        self.keymap = if standalone { &STANDALONE } else { &LAYOUT };
+        for state in self.prev_pressed.iter_mut().flatten() {
+            if state.pressed {
+                state.pressed_layer = u8::MAX;
+            }
+        }
+    }
+
+    /// The layer whose key at `(kr, kc)` is used while `layer` is active, which is below it if
+    /// its key there is dropped.
+    fn fall_through(&self, mut layer: usize, kr: usize, kc: usize) -> usize {
+        while layer > 0 && layer < self.keymap.len() && Key::Drop == self.keymap[layer][kr][kc] {
+            layer -= 1;
+        }
+        layer
     }
 
+    fn push<T, const N: usize>(&mut self, vec: &mut Vec<T, N>, item: T) {
+        if vec.push(item).is_err() {
+            self.dropped_keys += 1;
+        }
+    }
+
+    /// Updates the logic with the new state and fills `holds` and `actions` with what should be
+    /// sent to the host. The keys that changed did so at their time in `t_changed`, which may be
+    /// before `now` for keys of the other half.
+    ///
+    /// Nothing is allocated: keys that don't fit into their fixed-capacity buffer are dropped and
+    /// counted in [`KeyboardLogic::dropped_keys`]. The last slot of `actions` is reserved for the
+    /// normal presses, so combos are the first to be dropped when there are too many of them.
     pub fn update(
         &mut self,
         new_state: &[[bool; COLS]; ROWS],
-        timer: &Timer,
-        holds: &mut Vec<Keyboard>, // To be sent along with all keypresses.
-        actions: &mut Vec<SmallVec<[Keyboard; 4]>>,
+        t_changed: &Times,
+        now: Instant,
+        holds: &mut Holds, // To be sent along with all keypresses.
+        actions: &mut Actions,
     ) {
+        // The swap keys are never mirrored. Held keys are found in the layer they were pressed
+        // in, and new ones in the layer of the last update, since the layer keys may be mirrored.
+        let mut swap_keys = [[false; COLS]; ROWS];
+        let mut swap_held = false;
+        for (ri, row) in new_state.iter().enumerate() {
+            for (ci, &pressed) in row.iter().enumerate() {
+                let state = self.prev_pressed[ri][ci];
+                let layer = if state.pressed {
+                    state.pressed_layer as usize
+                } else {
+                    self.fall_through(self.layer as usize, ri, ci)
+                };
+                match self.keymap.get(layer).map(|keys| keys[ri][ci]) {
+                    Some(Key::SwapHands) => {
+                        swap_keys[ri][ci] = true;
+                        swap_held |= pressed;
+                    }
+                    Some(Key::SwapHandsToggle) => {
+                        swap_keys[ri][ci] = true;
+                        if pressed && !state.pressed {
+                            self.swap_toggled = !self.swap_toggled;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let swapped = swap_held != self.swap_toggled;
+
+        // The position in the keymap of each key, which stays mirrored as long as the key is held
+        // if the hands were swapped when it was pressed.
+        let mut positions = [[(0, 0); COLS]; ROWS];
+        for (ri, row) in positions.iter_mut().enumerate() {
+            for (ci, position) in row.iter_mut().enumerate() {
+                let state = &mut self.prev_pressed[ri][ci];
+                if new_state[ri][ci] && !state.pressed {
+                    state.pressed_swapped = swapped && !swap_keys[ri][ci];
+                }
+                *position = if state.pressed_swapped {
+                    (ri, MIRROR.get(ri).map_or(ci, |row| row[ci] as usize))
+                } else {
+                    (ri, ci)
+                };
+            }
+        }
+
         let mut current_layer: usize = 0;
 
-        let mut normal_presses = SmallVec::new();
-        for ri in 0..ROWS {
-            for ci in 0..COLS {
-                if new_state[ri][ci] {
-                    if let Key::LayerChange(n) = layout::LAYOUT[0][ri][ci] {
+        let mut normal_presses = Report::new();
+        for (row, positions) in new_state.iter().zip(positions.iter()) {
+            for (&pressed, &(kr, kc)) in row.iter().zip(positions.iter()) {
+                if pressed {
+                    if let Key::LayerChange(n) = self.keymap[0][kr][kc] {
                         current_layer += n as usize;
                     }
                 }
             }
         }
 
+        self.layer = current_layer as u8;
+
         let mut used_layer = [[current_layer as u8; COLS]; ROWS];
-        for ri in 0..ROWS {
-            for ci in 0..COLS {
-                let mut cur_layer = current_layer;
-                while cur_layer > 0 && Key::Drop == layout::LAYOUT[cur_layer][ri][ci] {
-                    cur_layer -= 1;
-                }
-                used_layer[ri][ci] = cur_layer as u8;
+        for (row, positions) in used_layer.iter_mut().zip(positions.iter()) {
+            for (layer, &(kr, kc)) in row.iter_mut().zip(positions.iter()) {
+                *layer = self.fall_through(current_layer, kr, kc) as u8;
             }
         }
 
         for ri in 0..ROWS {
             for ci in 0..COLS {
-                if used_layer[ri][ci] as usize > LAYOUT.len() {
-                    normal_presses.push(Keyboard::Q);
+                if used_layer[ri][ci] as usize >= self.keymap.len() {
+                    self.push(&mut normal_presses, Keyboard::Q);
                     continue;
                 }
                 let cur_pressed = new_state[ri][ci];
-                let prev_button_state = &mut self.prev_pressed[ri][ci];
-                let t = timer.get_counter();
+                let mut prev_button_state = self.prev_pressed[ri][ci];
+                let t = if cur_pressed != prev_button_state.pressed {
+                    t_changed[ri][ci]
+                } else {
+                    now
+                };
 
                 // So that if the layer is changed while any key is pressed it won't automatically
                 // press the corresponding key in the new layer.
@@ -153,52 +401,157 @@ impl KeyboardLogic {
                 }
 
                 if prev_button_state.pressed_layer == used_layer[ri][ci] {
-                    match LAYOUT[used_layer[ri][ci] as usize][ri][ci] {
+                    let (kr, kc) = positions[ri][ci];
+                    match self.keymap[used_layer[ri][ci] as usize][kr][kc] {
                         Key::Press(key) => {
                             if cur_pressed {
-                                normal_presses.push(key);
-                                self.t_last_key_sent = t;
+                                self.push(&mut normal_presses, key);
+                                self.t_last_key_sent = self.t_last_key_sent.max(t);
                             }
                         }
                         Key::Combo(k1, k2) => {
                             if cur_pressed && !prev_button_state.pressed {
-                                let mut sv = SmallVec::new();
-                                sv.push(k1);
-                                sv.push(k2);
-                                actions.push(sv);
-                                self.t_last_key_sent = t;
+                                if actions.len() + 1 < actions.capacity() {
+                                    let mut report = Report::new();
+                                    self.push(&mut report, k1);
+                                    self.push(&mut report, k2);
+                                    self.push(actions, report);
+                                } else {
+                                    self.dropped_keys += 2;
+                                }
+                                self.t_last_key_sent = self.t_last_key_sent.max(t);
                             }
                         }
                         Key::Empty => {}
                         Key::Hold(key) => {
                             if cur_pressed {
-                                holds.push(key);
+                                self.push(holds, key);
                             }
                         }
                         Key::OnClick(click_key, hold_mod, ms) => {
                             if cur_pressed {
-                                holds.push(hold_mod);
+                                self.push(holds, hold_mod);
                             } else if !cur_pressed
                                 && prev_button_state.pressed
                                 && (t - prev_button_state.t_change).ticks() < ms * 1000
                                 && prev_button_state.t_change.ticks()
                                     >= self.t_last_key_sent.ticks()
                             {
-                                normal_presses.push(click_key);
-                                self.t_last_key_sent = t;
+                                self.push(&mut normal_presses, click_key);
+                                self.t_last_key_sent = self.t_last_key_sent.max(t);
                             }
                         }
                         Key::Drop => {}
                         Key::LayerChange(_) => {}
+                        Key::SwapHands | Key::SwapHandsToggle => {}
                     }
                 }
                 if cur_pressed != prev_button_state.pressed {
                     prev_button_state.t_change = t;
                 }
                 prev_button_state.pressed = new_state[ri][ci];
+                self.prev_pressed[ri][ci] = prev_button_state;
             }
         }
 
-        actions.push(normal_presses);
+        self.push(actions, normal_presses);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Updates `logic` with the key at `(ri, ci)` changing to `pressed` at `t_ms`, seen at `now_ms`.
+    fn change(
+        logic: &mut KeyboardLogic,
+        state: &mut [[bool; COLS]; ROWS],
+        (ri, ci): (usize, usize),
+        pressed: bool,
+        t_ms: u64,
+        now_ms: u64,
+    ) -> Actions {
+        state[ri][ci] = pressed;
+        let mut t_changed = [[Instant::from_ticks(0); COLS]; ROWS];
+        t_changed[ri][ci] = Instant::from_ticks(t_ms * 1000);
+        let (mut holds, mut actions) = (Holds::new(), Actions::new());
+        logic.update(
+            state,
+            &t_changed,
+            Instant::from_ticks(now_ms * 1000),
+            &mut holds,
+            &mut actions,
+        );
+        actions
+    }
+
+    #[test]
+    fn taps_are_timed_by_when_keys_changed() {
+        // Escape when tapped, shift when held for longer than 150 ms.
+        let key = (1, 0);
+        let mut logic = KeyboardLogic::new(Instant::from_ticks(0));
+        let mut state = [[false; COLS]; ROWS];
+        change(&mut logic, &mut state, key, true, 10, 20);
+        // The release only arrives late, from the other half.
+        let actions = change(&mut logic, &mut state, key, false, 100, 300);
+        assert!(actions.iter().any(|report| report == &[Keyboard::Escape]));
+
+        change(&mut logic, &mut state, key, true, 400, 400);
+        let actions = change(&mut logic, &mut state, key, false, 600, 600);
+        assert!(actions.iter().all(|report| report.is_empty()));
+    }
+
+    #[test]
+    fn swapped_keys_stay_mirrored_until_released() {
+        let swap = (0, 0);
+        let a = (1, 1);
+        let mut logic = KeyboardLogic::new(Instant::from_ticks(0));
+        let mut state = [[false; COLS]; ROWS];
+        change(&mut logic, &mut state, swap, true, 10, 10);
+        change(&mut logic, &mut state, swap, false, 20, 20);
+        // The left half types the letters of the right half.
+        let actions = change(&mut logic, &mut state, a, true, 30, 30);
+        assert!(actions.iter().any(|report| report == &[Keyboard::O]));
+
+        // Swapping back while the key is held doesn't move it.
+        change(&mut logic, &mut state, swap, true, 40, 40);
+        let actions = change(&mut logic, &mut state, swap, false, 50, 50);
+        assert!(actions.iter().any(|report| report == &[Keyboard::O]));
+        let actions = change(&mut logic, &mut state, a, false, 60, 60);
+        assert!(actions.iter().all(|report| report.is_empty()));
+
+        let actions = change(&mut logic, &mut state, a, true, 70, 70);
+        assert!(actions.iter().any(|report| report == &[Keyboard::A]));
+    }
+
+    #[test]
+    fn held_swap_key_mirrors_until_released() {
+        let (layer, swap) = ((3, 4), (0, 0));
+        let a = (1, 1);
+        let mut logic = KeyboardLogic::new(Instant::from_ticks(0));
+        let mut state = [[false; COLS]; ROWS];
+        // The swap key is on the layer of the left thumb key, and stays held once it's released.
+        change(&mut logic, &mut state, layer, true, 10, 10);
+        change(&mut logic, &mut state, swap, true, 20, 20);
+        change(&mut logic, &mut state, layer, false, 30, 30);
+        let actions = change(&mut logic, &mut state, a, true, 40, 40);
+        assert!(actions.iter().any(|report| report == &[Keyboard::O]));
+
+        // Releasing the swap key doesn't move the key held meanwhile.
+        let actions = change(&mut logic, &mut state, swap, false, 50, 50);
+        assert!(actions.iter().any(|report| report == &[Keyboard::O]));
+        change(&mut logic, &mut state, a, false, 60, 60);
+
+        let actions = change(&mut logic, &mut state, a, true, 70, 70);
+        assert!(actions.iter().any(|report| report == &[Keyboard::A]));
+    }
+
+    #[test]
+    fn mirror_is_symmetric() {
+        for (ri, row) in MIRROR.iter().enumerate() {
+            for (ci, &mirror) in row.iter().enumerate() {
+                assert_eq!(MIRROR[ri][mirror as usize] as usize, ci);
+            }
+        }
     }
 }
@@ -1,24 +1,69 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+// The firmware entry point isn't built for host tests.
+#![cfg_attr(test, allow(dead_code))]
 
-extern crate alloc;
+#[cfg(any(test, feature = "modules"))]
+mod bus;
 mod buttonmatrix;
+mod clock;
 mod comms;
+mod console;
+mod debounce;
+mod diagnostics;
 mod encoding;
+mod flash;
+mod ghosting;
+#[cfg(any(test, feature = "single-wire"))]
+mod halfduplex;
+mod halves;
 mod hardware;
+#[cfg(not(feature = "pio-link"))]
+mod irquart;
 mod layout;
 mod master;
+#[cfg(test)]
+mod mock;
+#[cfg(any(test, feature = "module"))]
+mod module;
+#[cfg(feature = "pio-scan")]
+mod piomatrix;
+#[cfg(feature = "pio-link")]
+mod piouart;
+mod protocol;
+mod role;
+mod settings;
+#[cfg(not(feature = "module"))]
+mod setup;
 mod slave;
+mod transport;
+mod update;
 
-fn start() -> ! {
-    // Hardware setup.
+use rp_pico::pac::{CorePeripherals, Peripherals};
 
-    // slave::run()
-    master::run()
+#[cfg(not(feature = "module"))]
+use role::Role;
 
-    // if cfg!(slave) {
-    //     slave::run()
-    // } else {
-    //     master::run();
-    // }
+#[cfg(not(feature = "module"))]
+fn start() -> ! {
+    flash::boot();
+    let mut pac = Peripherals::take().unwrap();
+    let core = CorePeripherals::take().unwrap();
+    let settings = flash::settings();
+    match role::detect(&mut pac, settings) {
+        Some((Role::Master, hand)) => master::run(pac, core, hand),
+        Some((Role::Slave, hand)) => slave::run(pac, core, hand),
+        None => setup::run(pac, core, settings),
+    }
+}
+
+#[cfg(feature = "module")]
+fn start() -> ! {
+    flash::boot();
+    let mut pac = Peripherals::take().unwrap();
+    let core = CorePeripherals::take().unwrap();
+    match role::detect_module(&mut pac) {
+        module::Kind::Numpad => module::run(pac, core, &module::NUMPAD),
+        module::Kind::ThumbCluster => module::run(pac, core, &module::THUMB_CLUSTER),
+    }
 }
@@ -1,7 +1,13 @@
-use alloc::vec::Vec;
+use core::fmt::Write;
+
 use cortex_m::delay::Delay;
-use embedded_hal::timer::CountDown;
-use fugit::{ExtU32, RateExtU32};
+use embedded_hal::{
+    digital::v2::{OutputPin, PinState},
+    timer::CountDown,
+};
+use fugit::ExtU32;
+#[cfg(any(not(feature = "pio-link"), feature = "modules"))]
+use fugit::RateExtU32;
 // use panic_probe as _;
 
 use rp_pico as bsp;
@@ -9,10 +15,9 @@ use rp_pico as bsp;
 use bsp::{
     hal::{
         clocks::{init_clocks_and_plls, Clock},
-        gpio::{DynPin, Function, Uart},
         rom_data::reset_to_usb_boot,
         sio::Sio,
-        uart::{DataBits, StopBits, UartConfig},
+        timer::Instant,
         watchdog::Watchdog,
         Timer,
     },
@@ -28,18 +33,106 @@ use usb_device::{
 use usbd_human_interface_device::{
     device::keyboard::NKROBootKeyboardConfig, usb_class::UsbHidClassBuilder, UsbHidError,
 };
+use usbd_serial::SerialPort;
+
+use crate::{
+    buttonmatrix::DiodeDirection,
+    comms::{ComLink, Latency, LinkState, Update},
+    console::Console,
+    debounce::{Algorithm, Debouncer},
+    diagnostics::Diagnostics,
+    encoding::{decode, encoded_len},
+    flash,
+    ghosting::GhostFilter,
+    halves::{Grid, Hand, COLS, ROWS},
+    hardware,
+    layout::{self, Actions, Holds, KeyboardLogic, Times},
+    protocol::{Command, Greeting, Hello, Message, Peer, Setting, UpdateStatus, CHUNK_LEN},
+    role::Role,
+    settings::Settings,
+    update::Upload,
+};
 
+#[cfg(feature = "modules")]
+use crate::bus::{Bus, Placement, BUS_BAUD};
+#[cfg(not(feature = "pio-link"))]
+use crate::irquart::IrqUart;
+#[cfg(feature = "pio-scan")]
+use crate::piomatrix::PioMatrix;
+#[cfg(all(feature = "pio-link", not(feature = "single-wire")))]
+use crate::piouart::PioUart;
+#[cfg(any(not(feature = "pio-link"), feature = "modules"))]
+use crate::transport::DynUartPins;
+#[cfg(not(feature = "pio-scan"))]
 use crate::{
-    buttonmatrix::ButtonMatrix,
-    comms::ComLink,
-    encoding::decode,
-    layout::KeyboardLogic,
+    buttonmatrix::{PortMatrix, Scanner, SETTLE_FALLBACK, SETTLE_MARGIN},
+    hardware::SioBank,
+};
+#[cfg(feature = "single-wire")]
+use crate::{halfduplex::HalfDuplex, piouart::PioWire};
+#[cfg(any(not(feature = "pio-link"), feature = "modules"))]
+use bsp::hal::uart::{DataBits, StopBits, UartConfig};
+#[cfg(any(feature = "pio-scan", feature = "pio-link"))]
+use bsp::hal::{
+    gpio::{DynFunction, DynPinMode},
+    pio::PIOExt,
 };
 
+/// The baud rate of the split link. The UART's interrupt empties its FIFO in time at a higher
+/// rate than the main loop empties the PIO's.
+#[cfg(not(feature = "pio-link"))]
+const LINK_BAUD: u32 = 1_000_000;
+#[cfg(feature = "pio-link")]
+const LINK_BAUD: u32 = 115_200;
+const DEBOUNCE_MS: u8 = 5;
+const DEBOUNCE: Algorithm = Algorithm::EagerPress {
+    window_us: DEBOUNCE_MS as u32 * 1000,
+};
+/// The longest debounce window that can be set from the console, in milliseconds.
+const MAX_DEBOUNCE_MS: u8 = 30;
+const DIODES: DiodeDirection = DiodeDirection::Row2Col;
+/// Every key on this board has a diode.
+const GHOST_DETECTION: bool = false;
+/// Where the keys of each module go in the keymap, by device id.
+#[cfg(feature = "modules")]
+const MODULES: [(u8, Placement); 2] = [
+    // The numpad.
+    (
+        1,
+        Placement {
+            row: 5,
+            col: 0,
+            rows: 5,
+            cols: 4,
+        },
+    ),
+    // The thumb cluster.
+    (
+        2,
+        Placement {
+            row: 5,
+            col: 4,
+            rows: 2,
+            cols: 3,
+        },
+    ),
+];
+/// How fast the LED blinks while the split link is degraded.
+const LINK_BLINK_MS: u32 = 200;
+/// While the other half runs incompatible firmware, the LED flashes once every this many blinks.
+const INCOMPATIBLE_FLASH_PERIOD: u8 = 5;
+/// How long a firmware update for the slave may go without the host or the link making progress,
+/// and how long the slave may take to verify it.
+const UPLOAD_TIMEOUT_US: u64 = 5_000_000;
+/// How long the host has to be quiet after an update was aborted before its input is taken as
+/// commands again, so that the rest of the image isn't.
+const UPLOAD_QUIET_US: u64 = 500_000;
+/// How long the PIO scanner lets the columns settle, in units of 32 PIO cycles.
+#[cfg(feature = "pio-scan")]
+const PIO_SETTLE: u8 = 4;
+
 #[allow(unused)]
-pub fn run() -> ! {
-    let mut pac = Peripherals::take().unwrap();
-    let core = CorePeripherals::take().unwrap();
+pub fn run(mut pac: Peripherals, core: CorePeripherals, hand: Hand) -> ! {
     let mut watchdog = Watchdog::new(pac.WATCHDOG);
 
     let clocks = init_clocks_and_plls(
@@ -60,12 +153,15 @@ pub fn run() -> ! {
 
     let sio = Sio::new(pac.SIO);
 
-    let pins = Pins::new(
+    let mut gpio = hardware::pins_by_number(Pins::new(
         pac.IO_BANK0,
         pac.PADS_BANK0,
         sio.gpio_bank0,
         &mut pac.RESETS,
-    );
+    ));
+    let mut pin = |num: u8| gpio[num as usize].take().unwrap();
+    let half = hand.half();
+    let remote = hand.other().half();
 
     // unsafe {
     //     hardware::serial::start(pac.USBCTRL_REGS, pac.USBCTRL_DPRAM, clocks.usb_clock, &mut pac.RESETS);
@@ -80,133 +176,336 @@ pub fn run() -> ! {
     let mut keyboard = UsbHidClassBuilder::new()
         .add_device(NKROBootKeyboardConfig::default())
         .build(&usb_bus);
+    let mut console = Console::new(SerialPort::new(&usb_bus));
 
     let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x1209, 0x0001))
         .manufacturer("usbd-humane-interface-device")
         .product("Custom Keyboard")
         .serial_number("TEST")
+        .composite_with_iads()
         .build();
 
-    let mut rows = [
-        DynPin::from(pins.gpio8),
-        DynPin::from(pins.gpio10),
-        DynPin::from(pins.gpio15),
-        DynPin::from(pins.gpio13),
-        DynPin::from(pins.gpio12),
-    ];
-    rows.iter_mut().for_each(|p| p.into_push_pull_output());
-
-    let mut cols = [
-        DynPin::from(pins.gpio7),
-        DynPin::from(pins.gpio6),
-        DynPin::from(pins.gpio22),
-        DynPin::from(pins.gpio26),
-        DynPin::from(pins.gpio2),
-        DynPin::from(pins.gpio0),
-    ];
-    cols.iter_mut().for_each(|p| p.into_pull_down_input());
-
-    let mut butmat = ButtonMatrix { rows, cols };
-
-    let uart_pins = (
-        pins.gpio16.into_mode::<Function<Uart>>(),
-        pins.gpio17.into_mode::<Function<Uart>>(),
-    );
-    let mut uart = rp_pico::hal::uart::UartPeripheral::new(pac.UART0, uart_pins, &mut pac.RESETS)
-        .enable(
-            UartConfig::new(115200.Hz(), DataBits::Eight, None, StopBits::One),
-            clocks.peripheral_clock.freq(),
+    let mut rows = half.rows.map(&mut pin);
+    let mut cols = half.cols.map(&mut pin);
+    cols.iter_mut().for_each(|p| match DIODES {
+        DiodeDirection::Row2Col => p.into_pull_down_input(),
+        DiodeDirection::Col2Row => p.into_pull_up_input(),
+    });
+
+    let mut diagnostics = Diagnostics::new();
+    // From when the slave saw its events to when they got here.
+    let mut event_delay = Latency::new();
+
+    #[cfg(feature = "pio-scan")]
+    let mut butmat = {
+        rows.iter_mut().for_each(|p| {
+            p.try_into_mode(DynPinMode::Function(DynFunction::Pio0))
+                .unwrap()
+        });
+        let (mut pio, sm0, _, _, _) = pac.PIO0.split(&mut pac.RESETS);
+        PioMatrix::new(
+            &mut pio,
+            sm0,
+            rows.map(|p| p.id().num),
+            cols.map(|p| p.id().num),
+            DIODES,
+            PIO_SETTLE,
+        )
+        .unwrap()
+    };
+
+    #[cfg(not(feature = "pio-scan"))]
+    let mut butmat = {
+        rows.iter_mut().for_each(|p| p.into_push_pull_output());
+        let row_bits = core::array::from_fn(|ri| rows[ri].id().num);
+        let mut butmat = PortMatrix {
+            cols: cols.map(|p| p.id().num),
+            rows,
+            bank: SioBank,
+            diodes: DIODES,
+            settle_reads: [SETTLE_FALLBACK; ROWS],
+        };
+        if let Err(err) = butmat.calibrate(row_bits, SETTLE_MARGIN) {
+            butmat.settle_reads = [SETTLE_FALLBACK; ROWS];
+            diagnostics.record_error(err, timer.get_counter().ticks());
+        }
+        butmat
+    };
+    let mut ghost_filter = GhostFilter::new(GHOST_DETECTION);
+    let mut debouncer = Debouncer::new(DEBOUNCE);
+    // The debounce window of both halves, which can be changed from the console.
+    let mut debounce_ms = DEBOUNCE_MS;
+
+    #[cfg(feature = "single-wire")]
+    let mut uart = {
+        let mut wire_pin = pin(half.link.0);
+        wire_pin.into_pull_up_input();
+        wire_pin
+            .try_into_mode(DynPinMode::Function(DynFunction::Pio1))
+            .unwrap();
+        let (mut pio, sm0, sm1, _, _) = pac.PIO1.split(&mut pac.RESETS);
+        let wire = PioWire::new(
+            &mut pio,
+            sm0,
+            sm1,
+            wire_pin.id().num,
+            LINK_BAUD,
+            clocks.system_clock.freq().to_Hz(),
         )
         .unwrap();
+        HalfDuplex::new(wire, &timer, Role::Master)
+    };
+
+    #[cfg(all(feature = "pio-link", not(feature = "single-wire")))]
+    let mut uart = {
+        let mut tx = pin(half.link.0);
+        tx.try_into_mode(DynPinMode::Function(DynFunction::Pio1))
+            .unwrap();
+        let mut rx = pin(half.link.1);
+        rx.into_pull_up_input();
+        let (mut pio, sm0, sm1, _, _) = pac.PIO1.split(&mut pac.RESETS);
+        PioUart::new(
+            &mut pio,
+            sm0,
+            sm1,
+            tx.id().num,
+            rx.id().num,
+            LINK_BAUD,
+            clocks.system_clock.freq().to_Hz(),
+        )
+        .unwrap()
+    };
+
+    #[cfg(not(feature = "pio-link"))]
+    let mut uart = {
+        let uart_pins = DynUartPins::new(pin(half.link.0), pin(half.link.1)).unwrap();
+        let uart = rp_pico::hal::uart::UartPeripheral::new(pac.UART0, uart_pins, &mut pac.RESETS)
+            .enable(
+                UartConfig::new(LINK_BAUD.Hz(), DataBits::Eight, None, StopBits::One),
+                clocks.peripheral_clock.freq(),
+            )
+            .unwrap();
+        unsafe { IrqUart::new(uart) }
+    };
+
+    #[cfg(feature = "modules")]
+    let mut bus_uart = {
+        // The modules only drive the line back while answering a poll.
+        let mut rx = pin(half.modules.1);
+        rx.into_pull_up_input();
+        let bus_pins = DynUartPins::new(pin(half.modules.0), rx).unwrap();
+        rp_pico::hal::uart::UartPeripheral::new(pac.UART1, bus_pins, &mut pac.RESETS)
+            .enable(
+                UartConfig::new(BUS_BAUD.Hz(), DataBits::Eight, None, StopBits::One),
+                clocks.peripheral_clock.freq(),
+            )
+            .unwrap()
+    };
+    #[cfg(feature = "modules")]
+    let mut bus = Bus::new();
 
     let mut tick_count_down = timer.count_down();
     tick_count_down.start(500.micros());
 
     let mut scan_count_down = timer.count_down();
-    scan_count_down.start(500.micros());
+    scan_count_down.start(250.micros());
 
     let mut led_on = false;
-    let mut led_pin = pins.led.into_push_pull_output();
+    let mut flash_count: u8 = 0;
+    let mut led_pin = pin(hardware::LED);
+    led_pin.into_push_pull_output();
     let mut blink_count_down = timer.count_down();
-    blink_count_down.start(500.millis());
+    blink_count_down.start(LINK_BLINK_MS.millis());
 
-    let mut tot_pressed = [[false; 12]; 5];
-    let mut prev_pressed: Option<[[bool; 12]; 5]> = None;
+    let mut tot_pressed: Grid = [[false; layout::COLS]; layout::ROWS];
 
-    let mut kblogic = KeyboardLogic::new(&timer);
+    let mut kblogic = KeyboardLogic::new(timer.get_counter());
+    let mut t_changed: Times = [[timer.get_counter(); layout::COLS]; layout::ROWS];
 
-    let mut slave_req = timer.count_down();
-    slave_req.start(10.millis());
-    let mut comms = ComLink::new(slave_req);
-
-    let mut t_last_read = Some(0);
+    let mut comms =
+        ComLink::<{ encoded_len(ROWS, COLS) }>::new(Hello::new(Peer::Master, ROWS, COLS));
+    // The incompatible peer that was last logged.
+    let mut logged_peer: Option<Greeting> = None;
+    // Whether the slave could be talked to in the previous iteration.
+    let mut compatible = false;
+    // Whether this firmware has talked to the slave, and so works well enough to be kept.
+    let mut confirmed = false;
+    // The firmware image being forwarded to the slave, and when the host or the link last made
+    // progress with it.
+    let mut upload: Option<Upload> = None;
+    let mut t_last_upload = 0;
+    // When the whole image was handed to the link, until the slave has reported on it.
+    let mut t_committed: Option<u64> = None;
+    // When the host last sent input that is being discarded since an update was aborted.
+    let mut t_discarding: Option<u64> = None;
 
     loop {
-        // if blink_count_down.wait().is_ok() {
-        //     if let Some(tl) = t_last_read {
-        //         if timer.get_counter().ticks() - tl < 200_000 {
-        //             blink_count_down.start(200.millis());
-        //         } else {
-        //             blink_count_down.start(500.millis());
-        //         }
-        //     }
-        //     if led_on {
-        //         led_pin.set_low().unwrap();
-        //     } else {
-        //         led_pin.set_high().unwrap();
-        //     }
-        //     led_on = !led_on;
-        // }
-
-        if uart.uart_is_readable() {
-            t_last_read = Some(timer.get_counter().ticks());
-        }
-        if let Some(buf) = comms.poll(&mut uart) {
-            let mut pressed = [[false; 6]; 5];
-            // println!("got {:?}", buf);
-            decode(buf, &mut pressed);
-            for ri in 0..5 {
-                for ci in 0..6 {
-                    tot_pressed[ri][6 + ci] = pressed[ri][ci];
+        // The LED is lit while the link is up, and blinks while it's degraded. It flashes briefly
+        // when the other half is there but can't be talked to.
+        if blink_count_down.wait().is_ok() {
+            flash_count = (flash_count + 1) % INCOMPATIBLE_FLASH_PERIOD;
+            led_on = match comms.link_state() {
+                LinkState::Lost => false,
+                _ if !comms.is_compatible() => comms.peer().is_some() && flash_count == 0,
+                LinkState::Connected => true,
+                LinkState::Degraded => !led_on,
+            };
+            led_pin.set_state(PinState::from(led_on)).unwrap();
+        }
+
+        // Whether the keyboard logic has to see a change of the state.
+        let mut changed = false;
+
+        match comms.poll(&mut uart, timer.get_counter().ticks()) {
+            Some(Update::Event(event)) => {
+                let (ri, ci) = (event.row as usize, event.col as usize);
+                if ri < ROWS && ci < COLS {
+                    let (ri, ci) = remote.position(ri, ci);
+                    tot_pressed[ri][ci] = event.pressed;
+                    // The slave's own time of the event, so that tap-hold is as accurate as on
+                    // this half.
+                    let now = timer.get_counter();
+                    let t = comms.local_time(event.time_us, now.ticks());
+                    if let Some(t) = t {
+                        event_delay.record(now.ticks() - t);
+                    }
+                    t_changed[ri][ci] = t.map_or(now, Instant::from_ticks);
                 }
+                changed = true;
+            }
+            Some(Update::State(buf)) => {
+                let mut pressed = [[false; COLS]; ROWS];
+                if decode(&buf, &mut pressed) {
+                    let before = tot_pressed;
+                    remote.place(&pressed, &mut tot_pressed);
+                    stamp(&before, &tot_pressed, timer.get_counter(), &mut t_changed);
+                    changed = true;
+                }
+            }
+            Some(Update::Firmware(status)) => {
+                match status {
+                    UpdateStatus::Verified => {
+                        _ = write!(console, "update verified, the slave restarts into it\r\n");
+                    }
+                    UpdateStatus::Failed(err) => {
+                        _ = write!(console, "update failed on the slave: {:?}\r\n", err);
+                    }
+                }
+                if upload.take().is_some() {
+                    t_discarding = Some(timer.get_counter().ticks());
+                }
+                t_committed = None;
+            }
+            Some(Update::Lost) => {
+                // Keys held on the slave can't be released anymore, so release them all.
+                let before = tot_pressed;
+                remote.place(&[[false; COLS]; ROWS], &mut tot_pressed);
+                stamp(&before, &tot_pressed, timer.get_counter(), &mut t_changed);
+                changed = true;
             }
+            None => {}
         }
 
-        if scan_count_down.wait().is_ok() {
-            if let Some(pressed) = butmat.scan(&mut delay) {
-                if pressed[4][0] && pressed[0][5] && pressed[0][0] {
-                    reset_to_usb_boot(0, 0);
-                }
-                for ri in 0..5 {
-                    for ci in 0..6 {
-                        tot_pressed[ri][5 - ci] = pressed[ri][ci];
+        #[cfg(feature = "modules")]
+        if let Some((id, update)) = bus.poll(&mut bus_uart, timer.get_counter().ticks()) {
+            if let Some((_, placement)) = MODULES.iter().find(|(module, _)| *module == id) {
+                let rows = bus.module(id).map_or(0, |module| module.rows as usize);
+                let before = tot_pressed;
+                placement.apply(&update, rows, &mut tot_pressed);
+                stamp(&before, &tot_pressed, timer.get_counter(), &mut t_changed);
+                changed = true;
+            }
+        }
+
+        if let Some(peer) = comms.peer().filter(|_| !comms.is_compatible()) {
+            if logged_peer != Some(peer) {
+                match peer {
+                    Greeting::Hello(hello) => {
+                        _ = write!(console, "incompatible peer: {:?}\r\n", hello);
+                    }
+                    Greeting::Incompatible { protocol } => {
+                        _ = write!(console, "peer of protocol version {}\r\n", protocol);
                     }
                 }
-                let mut holds = Vec::with_capacity(8);
-                let mut actions = Vec::with_capacity(8);
-                kblogic.update(&tot_pressed, &timer, &mut holds, &mut actions);
-                for pressed in actions {
-                    keyboard
-                        .device()
-                        .write_report(pressed.iter().copied().chain(holds.iter().copied()))
-                        .ok();
+                logged_peer = Some(peer);
+            }
+        }
+
+        if comms.is_compatible() != compatible {
+            compatible = comms.is_compatible();
+            // The slave starts with the default debounce window.
+            if compatible && debounce_ms != DEBOUNCE_MS {
+                comms.send(Message::Config(Setting::DebounceMs(debounce_ms)));
+            }
+        }
+
+        if !confirmed && comms.peer().is_some() {
+            flash::confirm();
+            confirmed = true;
+        }
+
+        // Without the other half, or with one it can't talk to, this one is used on its own.
+        let standalone = comms.link_state() == LinkState::Lost || !comms.is_compatible();
+        if standalone != kblogic.is_standalone() {
+            kblogic.set_standalone(standalone);
+            changed = true;
+        }
+
+        // The PIO scans the matrix while the loop goes on, and is only started on time.
+        #[cfg(feature = "pio-scan")]
+        let scanned = {
+            let now = timer.get_counter().ticks();
+            if scan_count_down.wait().is_ok() {
+                butmat.start(now);
+            }
+            butmat.poll(now)
+        };
+        #[cfg(not(feature = "pio-scan"))]
+        let scanned = scan_count_down
+            .wait()
+            .is_ok()
+            .then(|| butmat.scan(&mut delay));
+        match scanned {
+            Some(Ok(raw)) => {
+                let now = timer.get_counter().ticks();
+                let pressed = ghost_filter.filter(&debouncer.update(&raw, now));
+                diagnostics.record(&raw, &pressed, now);
+                if pressed[4][0] && pressed[0][5] && pressed[0][0] {
+                    reset_to_usb_boot(0, 0);
                 }
-                // while !actions.is_empty() {
-                //     let action = actions.pop();
-                // }
+                let before = tot_pressed;
+                half.place(&pressed, &mut tot_pressed);
+                stamp(
+                    &before,
+                    &tot_pressed,
+                    Instant::from_ticks(now),
+                    &mut t_changed,
+                );
+                changed = true;
+            }
+            Some(Err(err)) => diagnostics.record_error(err, timer.get_counter().ticks()),
+            None => {}
+        }
 
-                // if let Some(prev_pressed) = prev_pressed {
-                //     for ri in 0..5 {
-                //         for ci in 0..12 {
-                //             if prev_pressed[ri][ci] && !tot_pressed[ri][ci] {
-                //                 println!("released {:?}", (ri, ci));
-                //             } else if !prev_pressed[ri][ci] && tot_pressed[ri][ci] {
-                //                 println!("pressed {:?}", (ri, ci));
-                //             }
-                //         }
-                //     }
-                // }
-                // prev_pressed = Some(tot_pressed);
+        if changed {
+            let mut holds = Holds::new();
+            let mut actions = Actions::new();
+            let layer = kblogic.layer();
+            kblogic.update(
+                &tot_pressed,
+                &t_changed,
+                timer.get_counter(),
+                &mut holds,
+                &mut actions,
+            );
+            if kblogic.layer() != layer {
+                comms.send(Message::Layer(kblogic.layer()));
+            }
+            for pressed in actions {
+                keyboard
+                    .device()
+                    .write_report(pressed.iter().copied().chain(holds.iter().copied()))
+                    .ok();
             }
         }
 
@@ -220,13 +519,163 @@ pub fn run() -> ! {
             }
         }
 
-        if usb_dev.poll(&mut [&mut keyboard]) {
+        if usb_dev.poll(&mut [&mut keyboard, console.port()]) {
             match keyboard.device().read_report() {
                 Err(UsbError::WouldBlock) => {}
                 Err(_e) => {
                     // error!("Failed to read keyeboard report");
                 }
-                Ok(_leds) => {}
+                Ok(leds) => {
+                    let bits = [
+                        leds.num_lock,
+                        leds.caps_lock,
+                        leds.scroll_lock,
+                        leds.compose,
+                        leds.kana,
+                    ]
+                    .iter()
+                    .enumerate()
+                    .fold(0, |bits, (i, &on)| bits | (on as u8) << i);
+                    comms.send(Message::HostLeds(bits));
+                }
+            }
+        }
+
+        if let Some(current) = &mut upload {
+            let now = timer.get_counter().ticks();
+            let mut buf = [0; CHUNK_LEN];
+            let count = console.read(&mut buf[..current.wanted()]);
+            let mut handed = false;
+            let sent = current.push(&buf[..count]).map(|()| {
+                current.flush(|message| {
+                    let accepted = comms.send(message);
+                    handed |= accepted;
+                    accepted
+                })
+            });
+            if count > 0 || handed {
+                t_last_upload = now;
+            }
+            match sent {
+                Ok(true) => {
+                    _ = write!(
+                        console,
+                        "update sent, waiting for the slave to verify it\r\n"
+                    );
+                    upload = None;
+                    t_committed = Some(now);
+                }
+                Ok(false) if now - t_last_upload < UPLOAD_TIMEOUT_US => {}
+                Ok(false) => {
+                    let (received, len) = current.progress();
+                    _ = write!(
+                        console,
+                        "update timed out after {} of {} bytes\r\n",
+                        received, len
+                    );
+                    upload = None;
+                    t_discarding = Some(now);
+                }
+                Err(err) => {
+                    _ = write!(console, "update failed: {:?}\r\n", err);
+                    upload = None;
+                    t_discarding = Some(now);
+                }
+            }
+            continue;
+        }
+
+        if let Some(t) = t_committed {
+            let now = timer.get_counter().ticks();
+            if now - t >= UPLOAD_TIMEOUT_US {
+                _ = write!(console, "the slave didn't report on the update\r\n");
+                t_committed = None;
+            }
+        }
+
+        if let Some(t) = t_discarding {
+            let now = timer.get_counter().ticks();
+            let mut buf = [0; CHUNK_LEN];
+            if console.read(&mut buf) > 0 {
+                t_discarding = Some(now);
+            } else if now - t >= UPLOAD_QUIET_US {
+                t_discarding = None;
+            }
+            continue;
+        }
+
+        match console.poll() {
+            Some(b'u') if comms.can_update() => {
+                upload = Some(Upload::new());
+                t_last_upload = timer.get_counter().ticks();
+            }
+            Some(b'u') => {
+                _ = write!(console, "no slave to update\r\n");
+            }
+            Some(b'd') => {
+                _ = write!(
+                    console,
+                    "{}link: {:?}\r\npeer: {:?}\r\nrejected frames: {}\r\n\
+                     round trip: {}\r\nevent delay: {}\r\ndropped keys: {}\r\n",
+                    diagnostics.report(timer.get_counter().ticks()),
+                    comms.link_state(),
+                    comms.peer(),
+                    comms.rejected(),
+                    comms.round_trip(),
+                    event_delay,
+                    kblogic.dropped_keys()
+                );
+                #[cfg(not(feature = "pio-link"))]
+                {
+                    _ = write!(console, "link bytes dropped: {}\r\n", uart.dropped());
+                }
+            }
+            Some(b'b') => {
+                comms.send(Message::Command(Command::Bootloader));
+            }
+            Some(command @ (b'm' | b's' | b'a')) => {
+                let role = match command {
+                    b'm' => Some(Role::Master),
+                    b's' => Some(Role::Slave),
+                    _ => None,
+                };
+                flash::store_settings(Settings {
+                    role,
+                    ..flash::settings()
+                });
+                _ = write!(console, "stored role: {:?}, from the next boot\r\n", role);
+            }
+            Some(command @ (b'l' | b'r')) => {
+                let hand = match command {
+                    b'l' => Hand::Left,
+                    _ => Hand::Right,
+                };
+                flash::store_settings(Settings {
+                    hand: Some(hand),
+                    ..flash::settings()
+                });
+                _ = write!(console, "stored hand: {:?}, from the next boot\r\n", hand);
+            }
+            Some(command @ (b'+' | b'-')) => {
+                debounce_ms = match command {
+                    b'+' => debounce_ms.saturating_add(1).min(MAX_DEBOUNCE_MS),
+                    _ => debounce_ms.saturating_sub(1).max(1),
+                };
+                debouncer.set_window(debounce_ms as u32 * 1000);
+                comms.send(Message::Config(Setting::DebounceMs(debounce_ms)));
+                _ = write!(console, "debounce: {} ms\r\n", debounce_ms);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Records `t` as the time of the keys that changed between `before` and `after`.
+fn stamp(before: &Grid, after: &Grid, t: Instant, t_changed: &mut Times) {
+    for ((before, after), times) in before.iter().zip(after).zip(t_changed) {
+        for ((before, after), time) in before.iter().zip(after).zip(times) {
+            if before != after {
+                *time = t;
             }
         }
     }
@@ -0,0 +1,385 @@
+//! Virtual GPIO pins and links for testing without hardware.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    rc::Rc,
+    vec,
+    vec::Vec,
+};
+
+use embedded_hal::{
+    blocking::delay::DelayUs,
+    digital::v2::{InputPin, OutputPin},
+};
+
+use crate::{
+    buttonmatrix::{DiodeDirection, InputBank},
+    halfduplex::{Clock, Symbol, Wire},
+    transport::Transport,
+    update::{Flash, PAGE_LEN, SECTOR_LEN},
+};
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct PinError;
+
+struct Grid {
+    diodes: DiodeDirection,
+    pressed: Vec<Vec<bool>>,
+    /// The level each row is driven to.
+    row_levels: Vec<bool>,
+    failing_row: Option<usize>,
+    failing_col: Option<usize>,
+}
+
+impl Grid {
+    fn active_level(&self) -> bool {
+        self.diodes == DiodeDirection::Row2Col
+    }
+
+    /// A column reads as active if a pressed switch connects it to an active row, and otherwise
+    /// is pulled to the idle level.
+    fn col_level(&self, col: usize) -> bool {
+        let active = self.active_level();
+        let connected = self
+            .row_levels
+            .iter()
+            .zip(self.pressed.iter())
+            .any(|(&level, row)| level == active && row[col]);
+        connected == active
+    }
+}
+
+/// A virtual switch grid whose columns respond to the levels the rows are driven to.
+#[derive(Clone)]
+pub struct MockMatrix {
+    grid: Rc<RefCell<Grid>>,
+}
+
+impl MockMatrix {
+    pub fn new(cols: usize, rows: usize, diodes: DiodeDirection) -> Self {
+        let idle = diodes != DiodeDirection::Row2Col;
+        MockMatrix {
+            grid: Rc::new(RefCell::new(Grid {
+                diodes,
+                pressed: vec![vec![false; cols]; rows],
+                row_levels: vec![idle; rows],
+                failing_row: None,
+                failing_col: None,
+            })),
+        }
+    }
+
+    pub fn rows<const ROWS: usize>(&self) -> [MockRow; ROWS] {
+        core::array::from_fn(|index| MockRow {
+            grid: self.grid.clone(),
+            index,
+        })
+    }
+
+    pub fn cols<const COLS: usize>(&self) -> [MockCol; COLS] {
+        core::array::from_fn(|index| MockCol {
+            grid: self.grid.clone(),
+            index,
+        })
+    }
+
+    /// A bank with the columns at bits `0..COLS`, followed by the rows.
+    pub fn bank(&self) -> MockBank {
+        MockBank {
+            grid: self.grid.clone(),
+        }
+    }
+
+    pub fn set(&self, row: usize, col: usize, pressed: bool) {
+        self.grid.borrow_mut().pressed[row][col] = pressed;
+    }
+
+    /// Makes every access to the row's pin fail.
+    pub fn fail_row(&self, row: usize) {
+        self.grid.borrow_mut().failing_row = Some(row);
+    }
+
+    /// Makes every access to the column's pin fail.
+    pub fn fail_col(&self, col: usize) {
+        self.grid.borrow_mut().failing_col = Some(col);
+    }
+
+    pub fn row_level(&self, row: usize) -> bool {
+        self.grid.borrow().row_levels[row]
+    }
+}
+
+pub struct MockRow {
+    grid: Rc<RefCell<Grid>>,
+    index: usize,
+}
+
+impl MockRow {
+    fn drive(&mut self, level: bool) -> Result<(), PinError> {
+        let mut grid = self.grid.borrow_mut();
+        if grid.failing_row == Some(self.index) {
+            return Err(PinError);
+        }
+        grid.row_levels[self.index] = level;
+        Ok(())
+    }
+}
+
+impl OutputPin for MockRow {
+    type Error = PinError;
+
+    fn set_low(&mut self) -> Result<(), PinError> {
+        self.drive(false)
+    }
+
+    fn set_high(&mut self) -> Result<(), PinError> {
+        self.drive(true)
+    }
+}
+
+pub struct MockCol {
+    grid: Rc<RefCell<Grid>>,
+    index: usize,
+}
+
+impl InputPin for MockCol {
+    type Error = PinError;
+
+    fn is_high(&self) -> Result<bool, PinError> {
+        let grid = self.grid.borrow();
+        if grid.failing_col == Some(self.index) {
+            return Err(PinError);
+        }
+        Ok(grid.col_level(self.index))
+    }
+
+    fn is_low(&self) -> Result<bool, PinError> {
+        self.is_high().map(|high| !high)
+    }
+}
+
+pub struct MockBank {
+    grid: Rc<RefCell<Grid>>,
+}
+
+impl InputBank for MockBank {
+    fn read(&mut self) -> u32 {
+        let grid = self.grid.borrow();
+        let cols = grid.pressed[0].len();
+        let mut levels = 0;
+        for col in 0..cols {
+            levels |= (grid.col_level(col) as u32) << col;
+        }
+        for (row, &level) in grid.row_levels.iter().enumerate() {
+            levels |= (level as u32) << (cols + row);
+        }
+        levels
+    }
+}
+
+pub struct NoDelay;
+
+impl DelayUs<u16> for NoDelay {
+    fn delay_us(&mut self, _us: u16) {}
+}
+
+impl DelayUs<u32> for NoDelay {
+    fn delay_us(&mut self, _us: u32) {}
+}
+
+/// A delay that only adds up how long it was asked to wait.
+pub struct CountingDelay {
+    pub us: u32,
+}
+
+impl DelayUs<u32> for CountingDelay {
+    fn delay_us(&mut self, us: u32) {
+        self.us += us;
+    }
+}
+
+/// One end of an in-memory link, whose bytes arrive at the other end.
+pub struct Loopback {
+    rx: Rc<RefCell<VecDeque<u8>>>,
+    /// Where the bytes written go, which is every other end for the master of a bus.
+    tx: Vec<Rc<RefCell<VecDeque<u8>>>>,
+    /// Bytes still to be lost out of those written.
+    losing: usize,
+}
+
+impl Loopback {
+    pub fn pair() -> (Loopback, Loopback) {
+        let a = Rc::new(RefCell::new(VecDeque::new()));
+        let b = Rc::new(RefCell::new(VecDeque::new()));
+        (
+            Loopback {
+                rx: a.clone(),
+                tx: vec![b.clone()],
+                losing: 0,
+            },
+            Loopback {
+                rx: b,
+                tx: vec![a],
+                losing: 0,
+            },
+        )
+    }
+
+    /// Returns the master's end of a bus, which every module hears, and the end of each module,
+    /// which only the master hears.
+    pub fn bus<const N: usize>() -> (Loopback, [Loopback; N]) {
+        let master_rx = Rc::new(RefCell::new(VecDeque::new()));
+        let modules = core::array::from_fn(|_| Loopback {
+            rx: Rc::new(RefCell::new(VecDeque::new())),
+            tx: vec![master_rx.clone()],
+            losing: 0,
+        });
+        let master = Loopback {
+            rx: master_rx,
+            tx: modules.iter().map(|m: &Loopback| m.rx.clone()).collect(),
+            losing: 0,
+        };
+        (master, modules)
+    }
+
+    /// Loses the next `count` bytes written to this end.
+    pub fn lose(&mut self, count: usize) {
+        self.losing += count;
+    }
+}
+
+impl Transport for Loopback {
+    fn read(&mut self) -> Option<u8> {
+        self.rx.borrow_mut().pop_front()
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        let lost = self.losing.min(bytes.len());
+        self.losing -= lost;
+        for tx in &self.tx {
+            tx.borrow_mut().extend(&bytes[lost..]);
+        }
+    }
+
+    fn is_readable(&self) -> bool {
+        !self.rx.borrow().is_empty()
+    }
+}
+
+struct Bus {
+    /// What each end has yet to receive.
+    queues: [VecDeque<Symbol>; 2],
+    log: Vec<Symbol>,
+    lose_break: bool,
+}
+
+/// One end of a shared wire, on which every symbol sent is heard by both ends.
+#[derive(Clone)]
+pub struct MockWire {
+    bus: Rc<RefCell<Bus>>,
+    index: usize,
+}
+
+impl MockWire {
+    pub fn pair() -> (MockWire, MockWire) {
+        let bus = Rc::new(RefCell::new(Bus {
+            queues: [VecDeque::new(), VecDeque::new()],
+            log: Vec::new(),
+            lose_break: false,
+        }));
+        (
+            MockWire {
+                bus: bus.clone(),
+                index: 0,
+            },
+            MockWire { bus, index: 1 },
+        )
+    }
+
+    /// Every symbol that has been on the wire.
+    pub fn symbols(&self) -> Vec<Symbol> {
+        self.bus.borrow().log.clone()
+    }
+
+    /// Makes the next break vanish from the wire.
+    pub fn lose_next_break(&self) {
+        self.bus.borrow_mut().lose_break = true;
+    }
+}
+
+impl Wire for MockWire {
+    fn send(&mut self, symbol: Symbol) -> bool {
+        let mut bus = self.bus.borrow_mut();
+        if symbol == Symbol::Break && bus.lose_break {
+            bus.lose_break = false;
+            return true;
+        }
+        bus.log.push(symbol);
+        bus.queues
+            .iter_mut()
+            .for_each(|queue| queue.push_back(symbol));
+        true
+    }
+
+    fn receive(&mut self) -> Option<Symbol> {
+        self.bus.borrow_mut().queues[self.index].pop_front()
+    }
+}
+
+#[derive(Clone)]
+pub struct MockClock {
+    now: Rc<Cell<u64>>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        MockClock {
+            now: Rc::new(Cell::new(0)),
+        }
+    }
+
+    pub fn advance(&self, us: u64) {
+        self.now.set(self.now.get() + us);
+    }
+}
+
+impl Clock for MockClock {
+    fn now_us(&self) -> u64 {
+        self.now.get()
+    }
+}
+
+/// A 2 MiB flash, which like the real one can only clear bits when programmed.
+pub struct MockFlash {
+    data: Vec<u8>,
+}
+
+impl MockFlash {
+    pub fn new() -> Self {
+        MockFlash {
+            data: vec![0xFF; 2 * 1024 * 1024],
+        }
+    }
+}
+
+impl Flash for MockFlash {
+    fn read(&mut self, offset: u32, buf: &mut [u8]) {
+        let offset = offset as usize;
+        buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+    }
+
+    fn erase(&mut self, offset: u32) {
+        assert_eq!(offset % SECTOR_LEN, 0);
+        let offset = offset as usize;
+        self.data[offset..offset + SECTOR_LEN as usize].fill(0xFF);
+    }
+
+    fn program(&mut self, offset: u32, page: &[u8; PAGE_LEN]) {
+        assert_eq!(offset as usize % PAGE_LEN, 0);
+        let offset = offset as usize;
+        for (byte, &new) in self.data[offset..offset + PAGE_LEN].iter_mut().zip(page) {
+            *byte &= new;
+        }
+    }
+}
@@ -0,0 +1,222 @@
+//! The firmware of a keyboard module on the bus of the master, like the numpad or the thumb
+//! cluster.
+//!
+//! Every module answers on the same line back to the master, so a module only drives its TX pin
+//! while it sends, and leaves the line to the master's pull-up the rest of the time.
+
+use cortex_m::delay::Delay;
+use embedded_hal::{
+    digital::v2::{OutputPin, PinState},
+    timer::CountDown,
+};
+use fugit::{ExtU32, RateExtU32};
+
+use rp_pico as bsp;
+
+use bsp::{
+    hal::{
+        clocks::{init_clocks_and_plls, Clock},
+        sio::Sio,
+        uart::{DataBits, Enabled, StopBits, UartConfig, UartPeripheral},
+        watchdog::Watchdog,
+        Timer,
+    },
+    pac::{CorePeripherals, Peripherals, IO_BANK0, UART0},
+    Pins,
+};
+
+use crate::{
+    bus::{BUS_BAUD, MODULE_STATE_LEN},
+    buttonmatrix::{DiodeDirection, PortMatrix, Scanner, SETTLE_FALLBACK, SETTLE_MARGIN},
+    comms::SlaveLink,
+    debounce::{Algorithm, Debouncer},
+    encoding::encode,
+    hardware::{self, SioBank},
+    protocol::{Event, Hello, Peer},
+    transport::{DynUartPins, Transport},
+};
+
+/// GPIO numbers of the TX and RX pins of the bus, on UART0.
+const BUS: (u8, u8) = (0, 1);
+const DEBOUNCE: Algorithm = Algorithm::EagerPress { window_us: 5_000 };
+const DIODES: DiodeDirection = DiodeDirection::Row2Col;
+
+/// Which module a board is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Numpad,
+    ThumbCluster,
+}
+
+/// How a module is wired. The ids match the placements of the modules on the master.
+pub struct Board<const ROWS: usize, const COLS: usize> {
+    /// The device id of the module on the bus.
+    pub id: u8,
+    /// GPIO numbers of the rows, from the top.
+    pub rows: [u8; ROWS],
+    /// GPIO numbers of the columns, from the left.
+    pub cols: [u8; COLS],
+}
+
+pub const NUMPAD: Board<5, 4> = Board {
+    id: 1,
+    rows: [2, 3, 4, 5, 6],
+    cols: [7, 8, 9, 10],
+};
+
+pub const THUMB_CLUSTER: Board<2, 3> = Board {
+    id: 2,
+    rows: [2, 3],
+    cols: [7, 8, 9],
+};
+
+/// The module's end of the bus, on UART0.
+struct BusUart {
+    uart: UartPeripheral<Enabled, UART0, DynUartPins<UART0>>,
+    tx: u8,
+}
+
+impl Transport for BusUart {
+    fn read(&mut self) -> Option<u8> {
+        self.uart.read()
+    }
+
+    /// Drives the line until the last stop bit of `bytes` has been sent.
+    fn write(&mut self, bytes: &[u8]) {
+        drive(self.tx, true);
+        self.uart.write(bytes);
+        // SAFETY: Reading the flags has no side effects.
+        while unsafe { (*UART0::ptr()).uartfr.read().busy().bit_is_set() } {}
+        drive(self.tx, false);
+    }
+
+    fn is_readable(&self) -> bool {
+        self.uart.is_readable()
+    }
+}
+
+/// Lets the GPIO `pin` drive its output, or releases it whatever its function.
+fn drive(pin: u8, driving: bool) {
+    // SAFETY: Only the output enable override of the pin is changed, and only the bus uses it.
+    let io = unsafe { &*IO_BANK0::ptr() };
+    io.gpio[pin as usize].gpio_ctrl.modify(|_, w| {
+        if driving {
+            w.oeover().normal()
+        } else {
+            w.oeover().disable()
+        }
+    });
+}
+
+pub fn run<const ROWS: usize, const COLS: usize>(
+    mut pac: Peripherals,
+    core: CorePeripherals,
+    board: &Board<ROWS, COLS>,
+) -> ! {
+    let mut watchdog = Watchdog::new(pac.WATCHDOG);
+
+    let clocks = init_clocks_and_plls(
+        bsp::XOSC_CRYSTAL_FREQ,
+        pac.XOSC,
+        pac.CLOCKS,
+        pac.PLL_SYS,
+        pac.PLL_USB,
+        &mut pac.RESETS,
+        &mut watchdog,
+    )
+    .ok()
+    .unwrap();
+
+    let mut delay = Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
+
+    let timer = Timer::new(pac.TIMER, &mut pac.RESETS);
+
+    let sio = Sio::new(pac.SIO);
+
+    let mut gpio = hardware::pins_by_number(Pins::new(
+        pac.IO_BANK0,
+        pac.PADS_BANK0,
+        sio.gpio_bank0,
+        &mut pac.RESETS,
+    ));
+    let mut pin = |num: u8| gpio[num as usize].take().unwrap();
+
+    let mut rows = board.rows.map(&mut pin);
+    rows.iter_mut().for_each(|p| p.into_push_pull_output());
+    let mut cols = board.cols.map(&mut pin);
+    cols.iter_mut().for_each(|p| match DIODES {
+        DiodeDirection::Row2Col => p.into_pull_down_input(),
+        DiodeDirection::Col2Row => p.into_pull_up_input(),
+    });
+    let mut butmat = PortMatrix {
+        cols: cols.map(|p| p.id().num),
+        rows,
+        bank: SioBank,
+        diodes: DIODES,
+        settle_reads: [SETTLE_FALLBACK; ROWS],
+    };
+    if butmat.calibrate(board.rows, SETTLE_MARGIN).is_err() {
+        butmat.settle_reads = [SETTLE_FALLBACK; ROWS];
+    }
+    let mut debouncer = Debouncer::new(DEBOUNCE);
+
+    // Released before it becomes the UART's, so the TX pin never drives the line while another
+    // module answers.
+    drive(BUS.0, false);
+    let mut bus = {
+        let bus_pins = DynUartPins::new(pin(BUS.0), pin(BUS.1)).unwrap();
+        let uart = UartPeripheral::new(pac.UART0, bus_pins, &mut pac.RESETS)
+            .enable(
+                UartConfig::new(BUS_BAUD.Hz(), DataBits::Eight, None, StopBits::One),
+                clocks.peripheral_clock.freq(),
+            )
+            .unwrap();
+        BusUart { uart, tx: BUS.0 }
+    };
+
+    let mut scan_count_down = timer.count_down();
+    scan_count_down.start(250.micros());
+
+    let mut led_pin = pin(hardware::LED);
+    led_pin.into_push_pull_output();
+
+    let mut prev_pressed = [[false; COLS]; ROWS];
+    // Every module sends a state of the same length. The empty state encodes to zeros.
+    let mut state = [0; MODULE_STATE_LEN];
+    let mut link = SlaveLink::on_bus(board.id, Hello::new(Peer::Module, ROWS, COLS));
+    loop {
+        let now = timer.get_counter().ticks();
+        link.update_link_state(now);
+        // The master only polls the modules, and sends them no messages.
+        _ = link.poll(&mut bus, now, &state);
+
+        // The LED shows that the master talks to the module.
+        led_pin
+            .set_state(PinState::from(link.is_compatible()))
+            .unwrap();
+
+        if scan_count_down.wait().is_err() {
+            continue;
+        }
+        // A failed scan keeps the last state until the next one.
+        let Ok(raw) = butmat.scan(&mut delay) else {
+            continue;
+        };
+        let now = timer.get_counter().ticks();
+        let pressed = debouncer.update(&raw, now);
+        for (ri, (row, prev_row)) in pressed.iter().zip(prev_pressed.iter()).enumerate() {
+            for (ci, (&key, &prev_key)) in row.iter().zip(prev_row.iter()).enumerate() {
+                if key != prev_key {
+                    link.push_event(Event {
+                        row: ri as u8,
+                        col: ci as u8,
+                        pressed: key,
+                        time_us: now as u32,
+                    });
+                }
+            }
+        }
+        prev_pressed = pressed;
+        encode(&prev_pressed, &mut state).unwrap();
+    }
+}
@@ -0,0 +1,149 @@
+use pio::{Assembler, InSource, JmpCondition, OutDestination, SetDestination};
+use rp_pico::hal::pio::{
+    PIOBuilder, PIOExt, PinDir, PinState, Running, Rx, StateMachine, StateMachineIndex, Tx,
+    UninitStateMachine, PIO,
+};
+
+use crate::buttonmatrix::{DiodeDirection, ScanError};
+
+/// How long a snapshot may take before the state machine is considered stalled. A snapshot takes
+/// a few microseconds.
+const STALL_US: u64 = 1_000;
+
+/// A button matrix scanned by a PIO state machine.
+///
+/// For every row word pushed to the TX FIFO, the state machine drives the rows accordingly, waits
+/// for the columns to settle and pushes the levels of all GPIOs to the RX FIFO. The CPU only has
+/// to start a snapshot with [`PioMatrix::start`], then keep the TX FIFO fed and collect the rows,
+/// which [`PioMatrix::poll`] does without blocking.
+///
+/// The row pins must have been switched to the function of the PIO block.
+pub struct PioMatrix<P, SM, const COLS: usize, const ROWS: usize>
+where
+    P: PIOExt,
+    SM: StateMachineIndex,
+{
+    sm: StateMachine<(P, SM), Running>,
+    rx: Rx<(P, SM)>,
+    tx: Tx<(P, SM)>,
+    /// The levels of every row pin while each of the rows is driven.
+    row_words: [u32; ROWS],
+    cols: [u8; COLS],
+    diodes: DiodeDirection,
+    /// The rows that have been requested from and returned by the state machine.
+    requested: usize,
+    received: usize,
+    snapshot: [[bool; COLS]; ROWS],
+    /// When the snapshot under way was started.
+    t_started: Option<u64>,
+}
+
+impl<P, SM, const COLS: usize, const ROWS: usize> PioMatrix<P, SM, COLS, ROWS>
+where
+    P: PIOExt,
+    SM: StateMachineIndex,
+{
+    /// Installs the scanning program and starts the state machine, given the GPIO numbers of the
+    /// rows and columns. The columns are sampled `settle` x 32 PIO cycles after driving each row,
+    /// where `settle` is at most 31.
+    pub fn new(
+        pio: &mut PIO<P>,
+        sm: UninitStateMachine<(P, SM)>,
+        rows: [u8; ROWS],
+        cols: [u8; COLS],
+        diodes: DiodeDirection,
+        settle: u8,
+    ) -> Option<Self> {
+        let mut a = Assembler::<{ pio::RP2040_MAX_PROGRAM_SIZE }>::new();
+        let mut wrap_target = a.label();
+        let mut wrap_source = a.label();
+        let mut wait = a.label();
+        a.bind(&mut wrap_target);
+        a.pull(false, true);
+        a.out(OutDestination::PINS, 32);
+        a.set(SetDestination::X, settle.min(31));
+        a.bind(&mut wait);
+        a.jmp_with_delay(JmpCondition::XDecNonZero, &mut wait, 31);
+        a.r#in(InSource::PINS, 32);
+        a.bind(&mut wrap_source);
+        a.push(false, true);
+        let program = a.assemble_with_wrap(wrap_source, wrap_target);
+
+        let installed = pio.install(&program).ok()?;
+        let (mut sm, rx, tx) = PIOBuilder::from_program(installed)
+            .out_pins(0, 32)
+            .in_pin_base(0)
+            .build(sm);
+
+        let idle = diodes == DiodeDirection::Col2Row;
+        let level = |active| {
+            if active != idle {
+                PinState::High
+            } else {
+                PinState::Low
+            }
+        };
+        sm.set_pins(rows.iter().map(|&row| (row, level(false))));
+        sm.set_pindirs(rows.iter().map(|&row| (row, PinDir::Output)));
+
+        let row_words = core::array::from_fn(|ri| {
+            rows.iter().enumerate().fold(0, |word, (other, &bit)| {
+                let high = level(other == ri) == PinState::High;
+                word | ((high as u32) << bit)
+            })
+        });
+
+        Some(PioMatrix {
+            sm: sm.start(),
+            rx,
+            tx,
+            row_words,
+            cols,
+            diodes,
+            requested: 0,
+            received: 0,
+            snapshot: [[false; COLS]; ROWS],
+            t_started: None,
+        })
+    }
+
+    /// Starts a snapshot at the time `now`, unless one is under way.
+    pub fn start(&mut self, now: u64) {
+        self.t_started.get_or_insert(now);
+    }
+
+    /// Keeps the state machine busy, and returns the snapshot once every row has been scanned.
+    /// If the snapshot isn't done [`STALL_US`] after it was started, the state machine is
+    /// restarted and [`ScanError::Stalled`] returned.
+    pub fn poll(&mut self, now: u64) -> Option<Result<[[bool; COLS]; ROWS], ScanError>> {
+        let t_started = self.t_started?;
+        while self.requested < ROWS && self.tx.write(self.row_words[self.requested]) {
+            self.requested += 1;
+        }
+
+        while let Some(levels) = self.rx.read() {
+            for (ci, &bit) in self.cols.iter().enumerate() {
+                self.snapshot[self.received][ci] = self.diodes.is_pressed(levels & (1 << bit) != 0);
+            }
+            self.received += 1;
+
+            if self.received == ROWS {
+                self.requested = 0;
+                self.received = 0;
+                self.t_started = None;
+                return Some(Ok(self.snapshot));
+            }
+        }
+
+        if now - t_started >= STALL_US {
+            self.sm.restart();
+            self.sm.drain_tx_fifo();
+            while self.rx.read().is_some() {}
+            self.requested = 0;
+            self.received = 0;
+            self.t_started = None;
+            return Some(Err(ScanError::Stalled));
+        }
+        None
+    }
+}
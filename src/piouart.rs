@@ -0,0 +1,261 @@
+use pio::{
+    Assembler, InSource, JmpCondition, MovDestination, MovOperation, MovSource, OutDestination,
+    Program, SetDestination, SideSet, WaitSource,
+};
+use rp_pico::hal::pio::{
+    PIOBuilder, PIOExt, PinDir, PinState, Running, Rx, ShiftDirection, StateMachine,
+    StateMachineIndex, Tx, UninitStateMachine, PIO,
+};
+
+#[cfg(feature = "single-wire")]
+use crate::halfduplex::{Symbol, Wire};
+#[cfg(not(feature = "single-wire"))]
+use crate::transport::Transport;
+
+/// The clock divisor for 8 PIO cycles per bit, as its integer and fractional parts.
+fn divisor(baud: u32, system_clock_hz: u32) -> (u16, u8) {
+    let divisor = (system_clock_hz as u64 * 256 / (8 * baud as u64)) as u32;
+    ((divisor >> 8) as u16, divisor as u8)
+}
+
+/// Samples the middle of each bit, starting 1.5 bits after the falling edge of the start bit,
+/// and pushes the byte to the top of the ISR if its stop bit is high. Otherwise the line is
+/// waited on to go high again, and if `breaks` is set, all ones are pushed to report the break.
+fn rx_program(breaks: bool) -> Program<{ pio::RP2040_MAX_PROGRAM_SIZE }> {
+    let mut a = Assembler::<{ pio::RP2040_MAX_PROGRAM_SIZE }>::new();
+    let mut wrap_target = a.label();
+    let mut wrap_source = a.label();
+    let mut bits = a.label();
+    let mut good_stop = a.label();
+    a.bind(&mut wrap_target);
+    a.wait(0, WaitSource::PIN, 0, false);
+    a.set_with_delay(SetDestination::X, 7, 10);
+    a.bind(&mut bits);
+    a.r#in(InSource::PINS, 1);
+    a.jmp_with_delay(JmpCondition::XDecNonZero, &mut bits, 6);
+    a.jmp(JmpCondition::PinHigh, &mut good_stop);
+    if breaks {
+        a.mov(MovDestination::ISR, MovOperation::Invert, MovSource::NULL);
+        a.push(false, true);
+    }
+    a.wait(1, WaitSource::PIN, 0, false);
+    a.jmp(JmpCondition::Always, &mut wrap_target);
+    a.bind(&mut good_stop);
+    a.bind(&mut wrap_source);
+    a.push(false, true);
+    a.assemble_with_wrap(wrap_source, wrap_target)
+}
+
+/// A UART made of two PIO state machines, so the split link can use any pair of GPIOs.
+///
+/// Frames are 8N1. Bytes with a bad stop bit are dropped.
+#[cfg(not(feature = "single-wire"))]
+pub struct PioUart<P, TX, RX>
+where
+    P: PIOExt,
+    TX: StateMachineIndex,
+    RX: StateMachineIndex,
+{
+    _tx_sm: StateMachine<(P, TX), Running>,
+    _rx_sm: StateMachine<(P, RX), Running>,
+    tx: Tx<(P, TX)>,
+    rx: Rx<(P, RX)>,
+}
+
+#[cfg(not(feature = "single-wire"))]
+impl<P, TX, RX> PioUart<P, TX, RX>
+where
+    P: PIOExt,
+    TX: StateMachineIndex,
+    RX: StateMachineIndex,
+{
+    /// Installs the programs and starts the state machines, given the GPIO numbers of the pins.
+    /// The TX pin must have been switched to the function of the PIO block, and the RX pin
+    /// should be pulled up.
+    pub fn new(
+        pio: &mut PIO<P>,
+        tx_sm: UninitStateMachine<(P, TX)>,
+        rx_sm: UninitStateMachine<(P, RX)>,
+        tx_pin: u8,
+        rx_pin: u8,
+        baud: u32,
+        system_clock_hz: u32,
+    ) -> Option<Self> {
+        let (int, frac) = divisor(baud, system_clock_hz);
+
+        // Holds the line high as the stop bit, or while idle.
+        let mut a = Assembler::<{ pio::RP2040_MAX_PROGRAM_SIZE }>::new_with_side_set(SideSet::new(
+            true, 1, false,
+        ));
+        let mut wrap_target = a.label();
+        let mut wrap_source = a.label();
+        let mut bits = a.label();
+        a.bind(&mut wrap_target);
+        a.pull_with_delay_and_side_set(false, true, 7, 1);
+        a.set_with_delay_and_side_set(SetDestination::X, 7, 7, 0);
+        a.bind(&mut bits);
+        a.out(OutDestination::PINS, 1);
+        a.bind(&mut wrap_source);
+        a.jmp_with_delay(JmpCondition::XDecNonZero, &mut bits, 6);
+        let program = a.assemble_with_wrap(wrap_source, wrap_target);
+        let installed = pio.install(&program).ok()?;
+        let (mut tx_sm, _, tx) = PIOBuilder::from_program(installed)
+            .out_pins(tx_pin, 1)
+            .side_set_pin_base(tx_pin)
+            .out_shift_direction(ShiftDirection::Right)
+            .clock_divisor_fixed_point(int, frac)
+            .build(tx_sm);
+        tx_sm.set_pins([(tx_pin, PinState::High)]);
+        tx_sm.set_pindirs([(tx_pin, PinDir::Output)]);
+
+        let program = rx_program(false);
+        let installed = pio.install(&program).ok()?;
+        let (mut rx_sm, rx, _) = PIOBuilder::from_program(installed)
+            .in_pin_base(rx_pin)
+            .jmp_pin(rx_pin)
+            .in_shift_direction(ShiftDirection::Right)
+            .clock_divisor_fixed_point(int, frac)
+            .build(rx_sm);
+        rx_sm.set_pindirs([(rx_pin, PinDir::Input)]);
+
+        Some(PioUart {
+            _tx_sm: tx_sm.start(),
+            _rx_sm: rx_sm.start(),
+            tx,
+            rx,
+        })
+    }
+}
+
+#[cfg(not(feature = "single-wire"))]
+impl<P, TX, RX> Transport for PioUart<P, TX, RX>
+where
+    P: PIOExt,
+    TX: StateMachineIndex,
+    RX: StateMachineIndex,
+{
+    fn read(&mut self) -> Option<u8> {
+        // The bits are shifted in from the top of the ISR.
+        self.rx.read().map(|word| (word >> 24) as u8)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            while !self.tx.write(byte as u32) {}
+        }
+    }
+
+    fn is_readable(&self) -> bool {
+        !self.rx.is_empty()
+    }
+}
+
+/// A single open-drain wire driven by two PIO state machines, for [`HalfDuplex`].
+///
+/// The pin is only ever driven low, and is otherwise left to be pulled up, so the halves can't
+/// short the line by driving it at the same time. Breaks are sent as a zero byte with a low stop
+/// bit.
+///
+/// [`HalfDuplex`]: crate::halfduplex::HalfDuplex
+#[cfg(feature = "single-wire")]
+pub struct PioWire<P, TX, RX>
+where
+    P: PIOExt,
+    TX: StateMachineIndex,
+    RX: StateMachineIndex,
+{
+    _tx_sm: StateMachine<(P, TX), Running>,
+    _rx_sm: StateMachine<(P, RX), Running>,
+    tx: Tx<(P, TX)>,
+    rx: Rx<(P, RX)>,
+}
+
+#[cfg(feature = "single-wire")]
+impl<P, TX, RX> PioWire<P, TX, RX>
+where
+    P: PIOExt,
+    TX: StateMachineIndex,
+    RX: StateMachineIndex,
+{
+    /// Installs the programs and starts the state machines, given the GPIO number of the wire.
+    /// The pin must have been switched to the function of the PIO block and be pulled up.
+    pub fn new(
+        pio: &mut PIO<P>,
+        tx_sm: UninitStateMachine<(P, TX)>,
+        rx_sm: UninitStateMachine<(P, RX)>,
+        pin: u8,
+        baud: u32,
+        system_clock_hz: u32,
+    ) -> Option<Self> {
+        let (int, frac) = divisor(baud, system_clock_hz);
+
+        // Shifts out 9 bits, the last being the stop bit, as pin directions: a one drives the
+        // line low and a zero releases it. The line is released between bytes.
+        let mut a = Assembler::<{ pio::RP2040_MAX_PROGRAM_SIZE }>::new_with_side_set(SideSet::new(
+            true, 1, true,
+        ));
+        let mut wrap_target = a.label();
+        let mut wrap_source = a.label();
+        let mut bits = a.label();
+        a.bind(&mut wrap_target);
+        a.pull_with_delay_and_side_set(false, true, 7, 0);
+        a.set_with_delay_and_side_set(SetDestination::X, 8, 7, 1);
+        a.bind(&mut bits);
+        a.out(OutDestination::PINDIRS, 1);
+        a.bind(&mut wrap_source);
+        a.jmp_with_delay(JmpCondition::XDecNonZero, &mut bits, 6);
+        let program = a.assemble_with_wrap(wrap_source, wrap_target);
+        let installed = pio.install(&program).ok()?;
+        let (mut tx_sm, _, tx) = PIOBuilder::from_program(installed)
+            .out_pins(pin, 1)
+            .side_set_pin_base(pin)
+            .out_shift_direction(ShiftDirection::Right)
+            .clock_divisor_fixed_point(int, frac)
+            .build(tx_sm);
+        tx_sm.set_pins([(pin, PinState::Low)]);
+        tx_sm.set_pindirs([(pin, PinDir::Input)]);
+
+        let installed = pio.install(&rx_program(true)).ok()?;
+        let (rx_sm, rx, _) = PIOBuilder::from_program(installed)
+            .in_pin_base(pin)
+            .jmp_pin(pin)
+            .in_shift_direction(ShiftDirection::Right)
+            .clock_divisor_fixed_point(int, frac)
+            .build(rx_sm);
+
+        Some(PioWire {
+            _tx_sm: tx_sm.start(),
+            _rx_sm: rx_sm.start(),
+            tx,
+            rx,
+        })
+    }
+}
+
+#[cfg(feature = "single-wire")]
+impl<P, TX, RX> Wire for PioWire<P, TX, RX>
+where
+    P: PIOExt,
+    TX: StateMachineIndex,
+    RX: StateMachineIndex,
+{
+    fn send(&mut self, symbol: Symbol) -> bool {
+        let dirs = match symbol {
+            // The data bits are inverted, and the stop bit releases the line.
+            Symbol::Byte(byte) => !byte as u32,
+            Symbol::Break => 0x1FF,
+        };
+        self.tx.write(dirs)
+    }
+
+    fn receive(&mut self) -> Option<Symbol> {
+        self.rx.read().map(|word| {
+            // Bytes only fill the top of the ISR.
+            if word & 0x00FF_FFFF != 0 {
+                Symbol::Break
+            } else {
+                Symbol::Byte((word >> 24) as u8)
+            }
+        })
+    }
+}
@@ -0,0 +1,756 @@
+//! Framing of the messages sent over the split link.
+//!
+//! Every frame is laid out as
+//!
+//! | sync | device, kind | length | sequence | payload        | CRC-16           |
+//! |------|--------------|--------|----------|----------------|------------------|
+//! | 0x7E | 1            | 1      | 1        | `length` bytes | 2, little endian |
+//!
+//! where the CRC covers everything between the sync byte and the CRC itself. The device is in the
+//! high nibble of its byte and the kind in the low one. The other half is device 0, and modules
+//! on the bus have their own ids.
+//!
+//! The framing, the protocol version at the start of a hello, and the [`FrameKind::Ack`],
+//! [`FrameKind::Update`] and [`FrameKind::UpdateStatus`] frames are the same in every protocol
+//! version, so that a half can always be updated to the protocol of the other.
+
+use heapless::Vec;
+
+use crate::update::UpdateError;
+
+pub const SYNC: u8 = 0x7E;
+pub const MAX_PAYLOAD: usize = 64;
+const HEADER: usize = 4;
+const TRAILER: usize = 2;
+pub const MAX_FRAME: usize = HEADER + MAX_PAYLOAD + TRAILER;
+/// The most bytes of a firmware image carried by one update message.
+pub const CHUNK_LEN: usize = 56;
+/// The version of the protocol, which both ends of a link must share.
+pub const PROTOCOL_VERSION: u8 = 1;
+/// The version of this firmware, as major, minor and patch.
+pub const FIRMWARE_VERSION: [u8; 3] = [
+    parse_u8(env!("CARGO_PKG_VERSION_MAJOR")),
+    parse_u8(env!("CARGO_PKG_VERSION_MINOR")),
+    parse_u8(env!("CARGO_PKG_VERSION_PATCH")),
+];
+/// The sequence number of a hello that asks for one back.
+pub const HELLO_REQUEST: u8 = 0;
+const HELLO_REPLY: u8 = 1;
+/// The number of device ids.
+#[allow(unused)]
+pub const MAX_DEVICES: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameKind {
+    /// Lets a module on the bus send what it has queued.
+    Poll = 1,
+    /// The encoded state of the slave, sent periodically. The payload starts with the sequence
+    /// number of the oldest unacknowledged event, and the frame carries the sequence number the
+    /// next event will get.
+    State = 2,
+    /// The active layer of the master.
+    Layer = 3,
+    /// The LEDs lit by the host, as in the HID keyboard LED report.
+    HostLeds = 4,
+    /// Changes a setting of the slave.
+    Config = 5,
+    /// Asks the slave to carry out a [`Command`].
+    Command = 6,
+    /// Acknowledges the message with the same sequence number. From the master, it acknowledges
+    /// every event up to the sequence number.
+    Ack = 7,
+    /// A key of the slave changing state.
+    Event = 8,
+    /// Tells the other end what firmware it talks to, as a [`Hello`]. The sequence number is
+    /// [`HELLO_REQUEST`] when a hello is wanted back.
+    Hello = 9,
+    /// A step of a firmware update of the slave, as an [`UpdateStep`]. The payload starts with
+    /// the step.
+    Update = 10,
+    /// Asks the slave for the time of its clock. The payload is the time of the master's clock,
+    /// as a little endian `u64`.
+    Sync = 11,
+    /// The time of the master's clock from a [`FrameKind::Sync`], followed by the time of the
+    /// slave's clock when it replied.
+    SyncReply = 12,
+    /// How the slave took a firmware update, as an [`UpdateStatus`].
+    UpdateStatus = 13,
+}
+
+impl FrameKind {
+    fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            1 => Some(FrameKind::Poll),
+            2 => Some(FrameKind::State),
+            3 => Some(FrameKind::Layer),
+            4 => Some(FrameKind::HostLeds),
+            5 => Some(FrameKind::Config),
+            6 => Some(FrameKind::Command),
+            7 => Some(FrameKind::Ack),
+            8 => Some(FrameKind::Event),
+            9 => Some(FrameKind::Hello),
+            10 => Some(FrameKind::Update),
+            11 => Some(FrameKind::Sync),
+            12 => Some(FrameKind::SyncReply),
+            13 => Some(FrameKind::UpdateStatus),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Setting {
+    /// The debounce window of the slave, in milliseconds.
+    DebounceMs(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Command {
+    /// Reboots the slave into the USB bootloader.
+    Bootloader = 1,
+}
+
+/// A step of sending a firmware image to the slave.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpdateStep {
+    /// Starts an update with an image of `len` bytes, whose CRC-32 is `crc`.
+    Begin { len: u32, crc: u32 },
+    /// The `len` bytes of the image at `offset`, in `data`. The rest of `data` is zeros.
+    Chunk {
+        offset: u32,
+        len: u8,
+        data: [u8; CHUNK_LEN],
+    },
+    /// Asks the slave to verify the image and restart into it.
+    Commit,
+}
+
+impl UpdateStep {
+    fn from_payload(payload: &[u8]) -> Option<Self> {
+        let word = |at: usize| {
+            Some(u32::from_le_bytes(
+                payload.get(at..at + 4)?.try_into().ok()?,
+            ))
+        };
+        match payload.first()? {
+            1 if payload.len() == 9 => Some(UpdateStep::Begin {
+                len: word(1)?,
+                crc: word(5)?,
+            }),
+            2 if payload.len() > 5 && payload.len() <= 5 + CHUNK_LEN => {
+                let mut data = [0; CHUNK_LEN];
+                data[..payload.len() - 5].copy_from_slice(&payload[5..]);
+                Some(UpdateStep::Chunk {
+                    offset: word(1)?,
+                    len: (payload.len() - 5) as u8,
+                    data,
+                })
+            }
+            3 if payload.len() == 1 => Some(UpdateStep::Commit),
+            _ => None,
+        }
+    }
+
+    fn to_payload(self) -> Vec<u8, MAX_PAYLOAD> {
+        let mut payload = Vec::new();
+        // The longest payload, of a full chunk, is shorter than `MAX_PAYLOAD`.
+        match self {
+            UpdateStep::Begin { len, crc } => {
+                _ = payload.push(1);
+                _ = payload.extend_from_slice(&len.to_le_bytes());
+                _ = payload.extend_from_slice(&crc.to_le_bytes());
+            }
+            UpdateStep::Chunk { offset, len, data } => {
+                _ = payload.push(2);
+                _ = payload.extend_from_slice(&offset.to_le_bytes());
+                _ = payload.extend_from_slice(&data[..len as usize]);
+            }
+            UpdateStep::Commit => _ = payload.push(3),
+        }
+        payload
+    }
+}
+
+/// What the slave reports once it has verified an update, or when a step of it failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpdateStatus {
+    /// The image matches its CRC, and the slave restarts into it.
+    Verified,
+    Failed(UpdateError),
+}
+
+impl UpdateStatus {
+    pub fn to_frame(self) -> Frame {
+        let status = match self {
+            UpdateStatus::Verified => 0,
+            UpdateStatus::Failed(UpdateError::BadLength) => 1,
+            UpdateStatus::Failed(UpdateError::OutOfOrder) => 2,
+            UpdateStatus::Failed(UpdateError::Corrupted) => 3,
+        };
+        // The payload is shorter than `MAX_PAYLOAD`.
+        Frame::new(FrameKind::UpdateStatus, 0, &[status]).unwrap()
+    }
+
+    pub fn from_frame(frame: &Frame) -> Option<Self> {
+        match (frame.kind, frame.payload.as_slice()) {
+            (FrameKind::UpdateStatus, &[0]) => Some(UpdateStatus::Verified),
+            (FrameKind::UpdateStatus, &[1]) => Some(UpdateStatus::Failed(UpdateError::BadLength)),
+            (FrameKind::UpdateStatus, &[2]) => Some(UpdateStatus::Failed(UpdateError::OutOfOrder)),
+            (FrameKind::UpdateStatus, &[3]) => Some(UpdateStatus::Failed(UpdateError::Corrupted)),
+            _ => None,
+        }
+    }
+}
+
+/// A message from the master to the slave, which the slave acknowledges.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Message {
+    Layer(u8),
+    HostLeds(u8),
+    Config(Setting),
+    Command(Command),
+    Update(UpdateStep),
+}
+
+impl Message {
+    pub fn to_frame(self, seq: u8) -> Frame {
+        // All payloads are shorter than `MAX_PAYLOAD`.
+        let frame = |kind, payload: &[u8]| Frame::new(kind, seq, payload).unwrap();
+        match self {
+            Message::Layer(layer) => frame(FrameKind::Layer, &[layer]),
+            Message::HostLeds(leds) => frame(FrameKind::HostLeds, &[leds]),
+            Message::Config(Setting::DebounceMs(ms)) => frame(FrameKind::Config, &[1, ms]),
+            Message::Command(command) => frame(FrameKind::Command, &[command as u8]),
+            Message::Update(step) => frame(FrameKind::Update, &step.to_payload()),
+        }
+    }
+
+    /// Returns the message carried by `frame`, or `None` if it isn't a valid message.
+    pub fn from_frame(frame: &Frame) -> Option<Self> {
+        match (frame.kind, frame.payload.as_slice()) {
+            (FrameKind::Layer, &[layer]) => Some(Message::Layer(layer)),
+            (FrameKind::HostLeds, &[leds]) => Some(Message::HostLeds(leds)),
+            (FrameKind::Config, &[1, ms]) => Some(Message::Config(Setting::DebounceMs(ms))),
+            (FrameKind::Command, &[1]) => Some(Message::Command(Command::Bootloader)),
+            (FrameKind::Update, payload) => UpdateStep::from_payload(payload).map(Message::Update),
+            _ => None,
+        }
+    }
+
+    /// Whether this message makes `other` obsolete, so that only the latest state is sent.
+    pub fn supersedes(&self, other: &Message) -> bool {
+        match (self, other) {
+            (Message::Layer(_), Message::Layer(_)) => true,
+            (Message::HostLeds(_), Message::HostLeds(_)) => true,
+            (Message::Config(Setting::DebounceMs(_)), Message::Config(Setting::DebounceMs(_))) => {
+                true
+            }
+            (Message::Command(a), Message::Command(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+const fn parse_u8(digits: &str) -> u8 {
+    let digits = digits.as_bytes();
+    let mut value = 0;
+    let mut i = 0;
+    while i < digits.len() {
+        value = value * 10 + (digits[i] - b'0');
+        i += 1;
+    }
+    value
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Peer {
+    Master = 1,
+    Slave = 2,
+    Module = 3,
+}
+
+/// What an end of a link tells the other about itself, so that incompatible firmware is refused
+/// instead of misunderstood.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Hello {
+    pub protocol: u8,
+    pub firmware: [u8; 3],
+    pub role: Peer,
+    /// The size of the matrix, which is empty for the master of the bus.
+    pub rows: u8,
+    pub cols: u8,
+}
+
+impl Hello {
+    /// The hello of this firmware.
+    pub fn new(role: Peer, rows: usize, cols: usize) -> Self {
+        Hello {
+            protocol: PROTOCOL_VERSION,
+            firmware: FIRMWARE_VERSION,
+            role,
+            rows: rows as u8,
+            cols: cols as u8,
+        }
+    }
+
+    /// Whether the end that sent `self` can talk to the one that sent `peer`. The halves must
+    /// have matrices of the same size, since each decodes the state of the other.
+    pub fn accepts(&self, peer: &Hello) -> bool {
+        peer.protocol == self.protocol
+            && match (self.role, peer.role) {
+                (Peer::Master, Peer::Slave) | (Peer::Slave, Peer::Master) => {
+                    (peer.rows, peer.cols) == (self.rows, self.cols)
+                }
+                (Peer::Master, Peer::Module) | (Peer::Module, Peer::Master) => true,
+                _ => false,
+            }
+    }
+
+    pub fn to_frame(self, request: bool) -> Frame {
+        let [major, minor, patch] = self.firmware;
+        let payload = [
+            self.protocol,
+            major,
+            minor,
+            patch,
+            self.role as u8,
+            self.rows,
+            self.cols,
+        ];
+        let seq = if request { HELLO_REQUEST } else { HELLO_REPLY };
+        // The payload is shorter than `MAX_PAYLOAD`.
+        Frame::new(FrameKind::Hello, seq, &payload).unwrap()
+    }
+
+    /// Returns the hello carried by `frame`, if it's of this protocol version.
+    pub fn from_frame(frame: &Frame) -> Option<Self> {
+        if frame.kind != FrameKind::Hello {
+            return None;
+        }
+        match *frame.payload.as_slice() {
+            [protocol, major, minor, patch, role, rows, cols] if protocol == PROTOCOL_VERSION => {
+                Some(Hello {
+                    protocol,
+                    firmware: [major, minor, patch],
+                    role: match role {
+                        1 => Peer::Master,
+                        2 => Peer::Slave,
+                        3 => Peer::Module,
+                        _ => return None,
+                    },
+                    rows,
+                    cols,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The hello of the other end, as far as it can be read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Greeting {
+    Hello(Hello),
+    /// A hello of another protocol version, whose layout may differ, so only the version is read.
+    Incompatible {
+        protocol: u8,
+    },
+}
+
+impl Greeting {
+    /// Returns the greeting carried by `frame`, or `None` if it isn't a hello or is a malformed
+    /// one of this protocol version.
+    pub fn from_frame(frame: &Frame) -> Option<Self> {
+        match *frame.payload.as_slice() {
+            _ if frame.kind != FrameKind::Hello => None,
+            [protocol, ..] if protocol != PROTOCOL_VERSION => {
+                Some(Greeting::Incompatible { protocol })
+            }
+            _ => Hello::from_frame(frame).map(Greeting::Hello),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    /// The device the frame is from or for.
+    pub device: u8,
+    pub kind: FrameKind,
+    pub seq: u8,
+    pub payload: Vec<u8, MAX_PAYLOAD>,
+}
+
+impl Frame {
+    /// Creates a frame of device 0, or `None` if the payload is longer than [`MAX_PAYLOAD`].
+    pub fn new(kind: FrameKind, seq: u8, payload: &[u8]) -> Option<Self> {
+        Some(Frame {
+            device: 0,
+            kind,
+            seq,
+            payload: Vec::from_slice(payload).ok()?,
+        })
+    }
+
+    /// Addresses the frame to or from `device`, which must be below [`MAX_DEVICES`].
+    pub fn for_device(mut self, device: u8) -> Self {
+        self.device = device;
+        self
+    }
+
+    pub fn encode(&self) -> Vec<u8, MAX_FRAME> {
+        let mut out = Vec::new();
+        // The payload is at most `MAX_PAYLOAD` long, so everything fits.
+        let kind = self.device << 4 | self.kind as u8;
+        _ = out.extend_from_slice(&[SYNC, kind, self.payload.len() as u8, self.seq]);
+        _ = out.extend_from_slice(&self.payload);
+        let crc = crc16(&out[1..]);
+        _ = out.extend_from_slice(&crc.to_le_bytes());
+        out
+    }
+}
+
+/// CRC-16/CCITT-FALSE.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// A key of the slave changing state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub row: u8,
+    pub col: u8,
+    pub pressed: bool,
+    /// When the change was seen, on the clock of the slave, in microseconds.
+    pub time_us: u32,
+}
+
+impl Event {
+    pub fn to_frame(self, seq: u8) -> Frame {
+        let t = self.time_us.to_le_bytes();
+        let payload = [
+            self.row,
+            self.col,
+            self.pressed as u8,
+            t[0],
+            t[1],
+            t[2],
+            t[3],
+        ];
+        // The payload is shorter than `MAX_PAYLOAD`.
+        Frame::new(FrameKind::Event, seq, &payload).unwrap()
+    }
+
+    pub fn from_frame(frame: &Frame) -> Option<Self> {
+        match (frame.kind, frame.payload.as_slice()) {
+            (FrameKind::Event, &[row, col, pressed @ (0 | 1), t0, t1, t2, t3]) => Some(Event {
+                row,
+                col,
+                pressed: pressed == 1,
+                time_us: u32::from_le_bytes([t0, t1, t2, t3]),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Extracts frames from a stream of bytes.
+///
+/// Bytes before a sync byte are skipped, and when a frame turns out to be corrupted only its sync
+/// byte is dropped, so a real frame that starts within the corrupted one is still found.
+pub struct FrameParser {
+    buffer: Vec<u8, MAX_FRAME>,
+    /// Frames that were rejected because of a bad CRC, length or kind.
+    pub rejected: u32,
+    /// Bytes that were skipped while looking for a sync byte.
+    pub skipped: u32,
+}
+
+impl FrameParser {
+    pub fn new() -> Self {
+        FrameParser {
+            buffer: Vec::new(),
+            rejected: 0,
+            skipped: 0,
+        }
+    }
+
+    pub fn push(&mut self, byte: u8) -> Option<Frame> {
+        if self.buffer.is_empty() && byte != SYNC {
+            self.skipped += 1;
+            return None;
+        }
+        // The buffer is drained as soon as it holds a complete frame, so there is always room.
+        _ = self.buffer.push(byte);
+
+        loop {
+            match self.parse() {
+                Ok(Some((frame, len))) => {
+                    self.drain(len);
+                    return Some(frame);
+                }
+                Ok(None) => return None,
+                Err(()) => {
+                    self.rejected += 1;
+                    self.drain(1);
+                    self.resync();
+                }
+            }
+        }
+    }
+
+    /// Parses the frame at the start of the buffer. Returns the frame and its encoded length, or
+    /// `None` if it's incomplete.
+    fn parse(&self) -> Result<Option<(Frame, usize)>, ()> {
+        if self.buffer.len() < HEADER {
+            return Ok(None);
+        }
+        let len = self.buffer[2] as usize;
+        if len > MAX_PAYLOAD {
+            return Err(());
+        }
+        let total = HEADER + len + TRAILER;
+        if self.buffer.len() < total {
+            return Ok(None);
+        }
+
+        let crc = u16::from_le_bytes([self.buffer[total - 2], self.buffer[total - 1]]);
+        if crc != crc16(&self.buffer[1..total - TRAILER]) {
+            return Err(());
+        }
+        let kind = FrameKind::from_u8(self.buffer[1] & 0x0F).ok_or(())?;
+        let frame = Frame::new(kind, self.buffer[3], &self.buffer[HEADER..HEADER + len])
+            .ok_or(())?
+            .for_device(self.buffer[1] >> 4);
+        Ok(Some((frame, total)))
+    }
+
+    /// Drops bytes until the buffer starts with a sync byte.
+    fn resync(&mut self) {
+        let start = self
+            .buffer
+            .iter()
+            .position(|&b| b == SYNC)
+            .unwrap_or(self.buffer.len());
+        self.skipped += start as u32;
+        self.drain(start);
+    }
+
+    fn drain(&mut self, count: usize) {
+        let len = self.buffer.len();
+        self.buffer.copy_within(count..len, 0);
+        self.buffer.truncate(len - count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_all(parser: &mut FrameParser, bytes: &[u8]) -> std::vec::Vec<Frame> {
+        bytes.iter().filter_map(|&b| parser.push(b)).collect()
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn round_trip() {
+        let frame = Frame::new(FrameKind::State, 42, &[1, 2, SYNC, 4]).unwrap();
+        let mut parser = FrameParser::new();
+        assert_eq!(parse_all(&mut parser, &frame.encode()), [frame]);
+    }
+
+    #[test]
+    fn round_trip_with_device() {
+        let frame = Frame::new(FrameKind::Event, 0, &[4, 5])
+            .unwrap()
+            .for_device(15);
+        let mut parser = FrameParser::new();
+        assert_eq!(parse_all(&mut parser, &frame.encode()), [frame]);
+    }
+
+    #[test]
+    fn skips_garbage_between_frames() {
+        let a = Frame::new(FrameKind::Ack, 1, &[]).unwrap();
+        let b = Frame::new(FrameKind::State, 2, &[9; 4]).unwrap();
+        let mut bytes = std::vec![0, 1, 2];
+        bytes.extend_from_slice(&a.encode());
+        bytes.extend_from_slice(&[0xFF, 0x00]);
+        bytes.extend_from_slice(&b.encode());
+
+        let mut parser = FrameParser::new();
+        assert_eq!(parse_all(&mut parser, &bytes), [a, b]);
+        assert_eq!(parser.skipped, 5);
+        assert_eq!(parser.rejected, 0);
+    }
+
+    #[test]
+    fn rejects_corrupted_frames() {
+        let frame = Frame::new(FrameKind::State, 7, &[1, 2, 3, 4]).unwrap();
+        for i in 1..frame.encode().len() {
+            let mut bytes = frame.encode();
+            bytes[i] ^= 0x10;
+            let mut parser = FrameParser::new();
+            assert_eq!(parse_all(&mut parser, &bytes), []);
+        }
+    }
+
+    #[test]
+    fn resyncs_after_dropped_byte() {
+        let a = Frame::new(FrameKind::State, 1, &[1, 2, 3, 4]).unwrap();
+        let b = Frame::new(FrameKind::State, 2, &[5, 6, 7, 8]).unwrap();
+        let mut bytes = std::vec::Vec::new();
+        // The first frame loses a byte of its payload, so it swallows the start of the second.
+        let mut damaged = a.encode();
+        damaged.remove(5);
+        bytes.extend_from_slice(&damaged);
+        bytes.extend_from_slice(&b.encode());
+        bytes.extend_from_slice(&a.encode());
+
+        let mut parser = FrameParser::new();
+        assert_eq!(parse_all(&mut parser, &bytes), [b, a]);
+        assert_eq!(parser.rejected, 1);
+    }
+
+    #[test]
+    fn message_round_trip() {
+        for message in [
+            Message::Layer(2),
+            Message::HostLeds(0b10),
+            Message::Config(Setting::DebounceMs(5)),
+            Message::Command(Command::Bootloader),
+            Message::Update(UpdateStep::Begin {
+                len: 0x0001_2345,
+                crc: 0xCBF4_3926,
+            }),
+            Message::Update(UpdateStep::Chunk {
+                offset: 56,
+                len: 3,
+                data: core::array::from_fn(|i| if i < 3 { i as u8 + 1 } else { 0 }),
+            }),
+            Message::Update(UpdateStep::Chunk {
+                offset: 0,
+                len: CHUNK_LEN as u8,
+                data: [SYNC; CHUNK_LEN],
+            }),
+            Message::Update(UpdateStep::Commit),
+        ] {
+            let frame = message.to_frame(9);
+            let mut parser = FrameParser::new();
+            let parsed = parse_all(&mut parser, &frame.encode());
+            assert_eq!(parsed, [frame]);
+            assert_eq!(Message::from_frame(&parsed[0]), Some(message));
+        }
+        let ack = Frame::new(FrameKind::Ack, 9, &[]).unwrap();
+        assert_eq!(Message::from_frame(&ack), None);
+    }
+
+    #[test]
+    fn update_status_round_trip() {
+        for status in [
+            UpdateStatus::Verified,
+            UpdateStatus::Failed(UpdateError::BadLength),
+            UpdateStatus::Failed(UpdateError::OutOfOrder),
+            UpdateStatus::Failed(UpdateError::Corrupted),
+        ] {
+            assert_eq!(UpdateStatus::from_frame(&status.to_frame()), Some(status));
+        }
+    }
+
+    #[test]
+    fn event_round_trip() {
+        let event = Event {
+            row: 4,
+            col: 5,
+            pressed: true,
+            time_us: 0xDEAD_BEEF,
+        };
+        assert_eq!(Event::from_frame(&event.to_frame(3)), Some(event));
+        let layer = Message::Layer(1).to_frame(3);
+        assert_eq!(Event::from_frame(&layer), None);
+    }
+
+    #[test]
+    fn hello_round_trip() {
+        let hello = Hello::new(Peer::Slave, 5, 6);
+        assert_eq!(Hello::from_frame(&hello.to_frame(true)), Some(hello));
+        assert_eq!(hello.to_frame(true).seq, HELLO_REQUEST);
+        assert_ne!(hello.to_frame(false).seq, HELLO_REQUEST);
+    }
+
+    #[test]
+    fn future_hellos_only_tell_their_protocol() {
+        let future = Frame::new(
+            FrameKind::Hello,
+            HELLO_REQUEST,
+            &[PROTOCOL_VERSION + 1, 9, 9],
+        )
+        .unwrap();
+        assert_eq!(
+            Greeting::from_frame(&future),
+            Some(Greeting::Incompatible {
+                protocol: PROTOCOL_VERSION + 1
+            })
+        );
+        let hello = Hello::new(Peer::Slave, 5, 6);
+        assert_eq!(
+            Greeting::from_frame(&hello.to_frame(false)),
+            Some(Greeting::Hello(hello))
+        );
+        assert_eq!(Greeting::from_frame(&Message::Layer(1).to_frame(3)), None);
+    }
+
+    #[test]
+    fn incompatible_hellos_are_refused() {
+        let master = Hello::new(Peer::Master, 5, 6);
+        assert!(master.accepts(&Hello::new(Peer::Slave, 5, 6)));
+        assert!(Hello::new(Peer::Slave, 5, 6).accepts(&master));
+        assert!(master.accepts(&Hello::new(Peer::Module, 2, 3)));
+        assert!(!master.accepts(&Hello::new(Peer::Slave, 4, 6)));
+        assert!(!master.accepts(&master));
+
+        // A future protocol version, with a longer hello.
+        let future =
+            Frame::new(FrameKind::Hello, HELLO_REQUEST, &[PROTOCOL_VERSION + 1; 9]).unwrap();
+        assert_eq!(Hello::from_frame(&future), None);
+
+        // A malformed hello of this protocol version.
+        let malformed =
+            Frame::new(FrameKind::Hello, HELLO_REQUEST, &[PROTOCOL_VERSION; 9]).unwrap();
+        assert_eq!(Hello::from_frame(&malformed), None);
+        assert_eq!(Greeting::from_frame(&malformed), None);
+    }
+
+    #[test]
+    fn newer_state_supersedes_older() {
+        assert!(Message::Layer(1).supersedes(&Message::Layer(0)));
+        assert!(!Message::Layer(1).supersedes(&Message::HostLeds(0)));
+        assert!(Message::Command(Command::Bootloader)
+            .supersedes(&Message::Command(Command::Bootloader)));
+    }
+
+    #[test]
+    fn rejects_oversized_length() {
+        let mut parser = FrameParser::new();
+        let frame = Frame::new(FrameKind::Ack, 3, &[]).unwrap();
+        let mut bytes = std::vec![SYNC, FrameKind::State as u8, 200];
+        bytes.extend_from_slice(&frame.encode());
+        assert_eq!(parse_all(&mut parser, &bytes), [frame]);
+    }
+}
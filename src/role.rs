@@ -0,0 +1,167 @@
+//! Which half talks to the computer, decided at boot so both halves can run the same firmware.
+
+use rp_pico::pac::Peripherals;
+
+#[cfg(any(test, feature = "module"))]
+use crate::module::Kind;
+use crate::{halves::Hand, settings::Settings};
+
+/// Reads high while the half is powered over USB.
+const VBUS_SENSE: usize = 24;
+/// Tying this pin to ground makes the half a slave, and tying it to 3V3 a master, whatever the
+/// USB power. Left floating, the role is the stored one, or follows the USB power.
+const ROLE_STRAP: usize = 28;
+/// Tying this pin to 3V3 makes the half the left one, and tying it to ground the right one. Left
+/// floating, the hand is the stored one. On a module, tying it to ground makes it the thumb
+/// cluster.
+const HAND_STRAP: usize = 27;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Connected to the computer over USB.
+    Master,
+    Slave,
+}
+
+/// Detects the role of this half from the strap pin, the `stored` settings and the USB power, and
+/// its hand from the other strap pin or the `stored` settings. Returns `None` if the hand is
+/// neither strapped nor stored, since each half has its own pins.
+///
+/// Only the GPIO banks are touched, and they are set up again by whichever role runs.
+#[allow(unused)]
+pub fn detect(pac: &mut Peripherals, stored: Settings) -> Option<(Role, Hand)> {
+    enable_gpio(pac);
+    let vbus = pac.SIO.gpio_in.read().bits() & (1 << VBUS_SENSE) != 0;
+    let role = choose(strap_levels(pac, ROLE_STRAP), stored.role, vbus);
+    Some((
+        role,
+        choose_hand(strap_levels(pac, HAND_STRAP), stored.hand)?,
+    ))
+}
+
+/// Detects which module this board is from the hand strap pin, which modules have no use for.
+#[cfg(feature = "module")]
+pub fn detect_module(pac: &mut Peripherals) -> Kind {
+    enable_gpio(pac);
+    choose_module(strap_levels(pac, HAND_STRAP))
+}
+
+fn enable_gpio(pac: &mut Peripherals) {
+    pac.RESETS
+        .reset
+        .modify(|_, w| w.io_bank0().clear_bit().pads_bank0().clear_bit());
+    while {
+        let done = pac.RESETS.reset_done.read();
+        done.io_bank0().bit_is_clear() || done.pads_bank0().bit_is_clear()
+    } {}
+}
+
+/// The levels the strap `pin` reads with a pull-up and with a pull-down.
+fn strap_levels(pac: &Peripherals, pin: usize) -> (bool, bool) {
+    let level = |pull_up: bool| {
+        pac.PADS_BANK0.gpio[pin]
+            .modify(|_, w| w.ie().set_bit().pue().bit(pull_up).pde().bit(!pull_up));
+        // Lets the pull charge the pin, which takes a few microseconds.
+        cortex_m::asm::delay(10_000);
+        pac.SIO.gpio_in.read().bits() & (1 << pin) != 0
+    };
+    (level(true), level(false))
+}
+
+/// Picks the role from the levels the strap pin reads with a pull-up and with a pull-down, then
+/// from the stored role.
+fn choose(strap_levels: (bool, bool), stored: Option<Role>, vbus: bool) -> Role {
+    match (strap_levels, stored) {
+        ((true, true), _) => Role::Master,
+        ((false, false), _) => Role::Slave,
+        (_, Some(role)) => role,
+        _ if vbus => Role::Master,
+        _ => Role::Slave,
+    }
+}
+
+/// Picks the module from the levels the hand strap pin reads with a pull-up and with a
+/// pull-down: tied to ground it's the thumb cluster, and otherwise the numpad.
+#[cfg(any(test, feature = "module"))]
+fn choose_module(strap_levels: (bool, bool)) -> Kind {
+    match strap_levels {
+        (false, false) => Kind::ThumbCluster,
+        _ => Kind::Numpad,
+    }
+}
+
+/// Picks the hand from the levels its strap pin reads with a pull-up and with a pull-down, then
+/// from the stored hand.
+fn choose_hand(strap_levels: (bool, bool), stored: Option<Hand>) -> Option<Hand> {
+    match strap_levels {
+        (true, true) => Some(Hand::Left),
+        (false, false) => Some(Hand::Right),
+        _ => stored,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strap_overrides_usb_power() {
+        assert_eq!(choose((true, true), None, false), Role::Master);
+        assert_eq!(choose((false, false), None, true), Role::Slave);
+        assert_eq!(
+            choose((false, false), Some(Role::Master), true),
+            Role::Slave
+        );
+    }
+
+    #[test]
+    fn stored_role_overrides_usb_power() {
+        assert_eq!(choose((true, false), Some(Role::Slave), true), Role::Slave);
+        assert_eq!(
+            choose((true, false), Some(Role::Master), false),
+            Role::Master
+        );
+    }
+
+    #[test]
+    fn floating_strap_follows_usb_power() {
+        assert_eq!(choose((true, false), None, true), Role::Master);
+        assert_eq!(choose((true, false), None, false), Role::Slave);
+    }
+
+    #[test]
+    fn strap_overrides_stored_hand() {
+        assert_eq!(
+            choose_hand((false, false), Some(Hand::Left)),
+            Some(Hand::Right)
+        );
+        assert_eq!(
+            choose_hand((true, true), Some(Hand::Right)),
+            Some(Hand::Left)
+        );
+    }
+
+    #[test]
+    fn floating_strap_uses_stored_hand() {
+        assert_eq!(
+            choose_hand((true, false), Some(Hand::Right)),
+            Some(Hand::Right)
+        );
+        assert_eq!(
+            choose_hand((true, false), Some(Hand::Left)),
+            Some(Hand::Left)
+        );
+    }
+
+    #[test]
+    fn hand_is_never_guessed() {
+        assert_eq!(choose_hand((true, false), None), None);
+    }
+
+    #[test]
+    fn modules_default_to_the_numpad() {
+        assert_eq!(choose_module((false, false)), Kind::ThumbCluster);
+        assert_eq!(choose_module((true, false)), Kind::Numpad);
+        assert_eq!(choose_module((true, true)), Kind::Numpad);
+    }
+}
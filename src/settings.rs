@@ -0,0 +1,91 @@
+//! Settings of the board kept in flash, set from the console and kept through updates.
+//!
+//! They have a sector of their own after the [`META`] sector, which every swap of the slots erases.
+
+use crate::{
+    halves::Hand,
+    role::Role,
+    update::{Flash, META, PAGE_LEN, SECTOR_LEN},
+};
+
+/// The offset into the flash of the sector that holds the settings.
+pub const SETTINGS: u32 = META + SECTOR_LEN;
+const SETTINGS_MAGIC: [u8; 4] = *b"KFCS";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Settings {
+    /// The role of the board whatever its USB power, unless a strap pin picks it.
+    pub role: Option<Role>,
+    /// Which half the board is, unless a strap pin picks it.
+    pub hand: Option<Hand>,
+}
+
+impl Settings {
+    /// Reads the settings, which are all unset on a board that never stored any.
+    pub fn read(flash: &mut impl Flash) -> Settings {
+        let mut bytes = [0; 6];
+        flash.read(SETTINGS, &mut bytes);
+        if bytes[..4] != SETTINGS_MAGIC {
+            return Settings::default();
+        }
+        Settings {
+            role: match bytes[4] {
+                1 => Some(Role::Master),
+                2 => Some(Role::Slave),
+                _ => None,
+            },
+            hand: match bytes[5] {
+                1 => Some(Hand::Left),
+                2 => Some(Hand::Right),
+                _ => None,
+            },
+        }
+    }
+
+    pub fn write(self, flash: &mut impl Flash) {
+        let mut page = [0xFF; PAGE_LEN];
+        page[..4].copy_from_slice(&SETTINGS_MAGIC);
+        page[4] = match self.role {
+            None => 0,
+            Some(Role::Master) => 1,
+            Some(Role::Slave) => 2,
+        };
+        page[5] = match self.hand {
+            None => 0,
+            Some(Hand::Left) => 1,
+            Some(Hand::Right) => 2,
+        };
+        flash.erase(SETTINGS);
+        flash.program(SETTINGS, &page);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mock::MockFlash,
+        update::{Meta, SlotState},
+    };
+
+    #[test]
+    fn erased_flash_has_no_settings() {
+        assert_eq!(Settings::read(&mut MockFlash::new()), Settings::default());
+    }
+
+    #[test]
+    fn settings_outlive_slot_swaps() {
+        let mut flash = MockFlash::new();
+        let settings = Settings {
+            role: Some(Role::Slave),
+            hand: Some(Hand::Right),
+        };
+        settings.write(&mut flash);
+        Meta {
+            state: SlotState::Pending,
+            sectors: 1,
+        }
+        .write(&mut flash);
+        assert_eq!(Settings::read(&mut flash), settings);
+    }
+}
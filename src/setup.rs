@@ -0,0 +1,80 @@
+//! The firmware of a half that doesn't know which half it is, since its hand is neither strapped
+//! nor stored. Its pins depend on the hand, so it scans nothing: the LED blinks, and the hand is
+//! stored from the USB serial port, with `l` or `r`.
+
+use cortex_m::peripheral::SCB;
+use embedded_hal::{digital::v2::ToggleableOutputPin, timer::CountDown};
+use fugit::ExtU32;
+
+use rp_pico as bsp;
+
+use bsp::{
+    hal::{clocks::init_clocks_and_plls, sio::Sio, watchdog::Watchdog, Timer},
+    pac::{CorePeripherals, Peripherals},
+    Pins,
+};
+
+use crate::{flash, halves::Hand, hardware, settings::Settings};
+
+/// How fast the LED blinks, faster than for anything else.
+const BLINK_MS: u32 = 50;
+
+pub fn run(mut pac: Peripherals, _core: CorePeripherals, stored: Settings) -> ! {
+    let mut watchdog = Watchdog::new(pac.WATCHDOG);
+
+    let clocks = init_clocks_and_plls(
+        bsp::XOSC_CRYSTAL_FREQ,
+        pac.XOSC,
+        pac.CLOCKS,
+        pac.PLL_SYS,
+        pac.PLL_USB,
+        &mut pac.RESETS,
+        &mut watchdog,
+    )
+    .ok()
+    .unwrap();
+
+    let timer = Timer::new(pac.TIMER, &mut pac.RESETS);
+
+    let sio = Sio::new(pac.SIO);
+    let pins = Pins::new(
+        pac.IO_BANK0,
+        pac.PADS_BANK0,
+        sio.gpio_bank0,
+        &mut pac.RESETS,
+    );
+    let mut led_pin = pins.led.into_push_pull_output();
+
+    unsafe {
+        hardware::serial::start(
+            pac.USBCTRL_REGS,
+            pac.USBCTRL_DPRAM,
+            clocks.usb_clock,
+            &mut pac.RESETS,
+        );
+    }
+
+    let mut blink_count_down = timer.count_down();
+    blink_count_down.start(BLINK_MS.millis());
+    loop {
+        if blink_count_down.wait().is_ok() {
+            led_pin.toggle().unwrap();
+        }
+        if !hardware::serial::available() {
+            continue;
+        }
+        let hand = match hardware::serial::read_byte() {
+            b'l' => Hand::Left,
+            b'r' => Hand::Right,
+            _ => {
+                hardware::serial::print!("which half is this? send l or r\r\n");
+                continue;
+            }
+        };
+        flash::store_settings(Settings {
+            hand: Some(hand),
+            ..stored
+        });
+        SCB::sys_reset();
+    }
+}
@@ -1,16 +1,19 @@
-use cortex_m::delay::Delay;
-use embedded_hal::{serial::Read, timer::CountDown};
-use fugit::{ExtU32, RateExtU32};
+use cortex_m::{delay::Delay, peripheral::SCB};
+use embedded_hal::{
+    digital::v2::{OutputPin, PinState},
+    timer::CountDown,
+};
+use fugit::ExtU32;
+#[cfg(not(feature = "pio-link"))]
+use fugit::RateExtU32;
 
 use rp_pico as bsp;
 
 use bsp::{
     hal::{
         clocks::{init_clocks_and_plls, Clock},
-        gpio::DynPin,
         rom_data::reset_to_usb_boot,
         sio::Sio,
-        uart::{DataBits, StopBits, UartConfig},
         watchdog::Watchdog,
         Timer,
     },
@@ -20,15 +23,63 @@ use bsp::{
 };
 
 use crate::{
-    buttonmatrix::ButtonMatrix,
-    encoding::encode,
+    buttonmatrix::DiodeDirection,
+    comms::{LinkState, SlaveLink},
+    debounce::{Algorithm, Debouncer},
+    diagnostics::Diagnostics,
+    encoding::{encode, encoded_len},
+    flash::{self, RomFlash},
+    ghosting::GhostFilter,
+    halves::{Hand, COLS, ROWS},
     hardware::{self},
+    protocol::{Command, Event, Hello, Message, Peer, Setting, UpdateStatus},
+    update::Download,
 };
 
+#[cfg(feature = "pio-scan")]
+use crate::piomatrix::PioMatrix;
+#[cfg(all(feature = "pio-link", not(feature = "single-wire")))]
+use crate::piouart::PioUart;
+#[cfg(not(feature = "pio-scan"))]
+use crate::{
+    buttonmatrix::{PortMatrix, Scanner, SETTLE_FALLBACK, SETTLE_MARGIN},
+    hardware::SioBank,
+};
+#[cfg(feature = "single-wire")]
+use crate::{halfduplex::HalfDuplex, piouart::PioWire, role::Role};
+#[cfg(not(feature = "pio-link"))]
+use crate::{irquart::IrqUart, transport::DynUartPins};
+#[cfg(not(feature = "pio-link"))]
+use bsp::hal::uart::{DataBits, StopBits, UartConfig};
+#[cfg(any(feature = "pio-scan", feature = "pio-link"))]
+use bsp::hal::{
+    gpio::{DynFunction, DynPinMode},
+    pio::PIOExt,
+};
+
+/// The baud rate of the split link. The UART's interrupt empties its FIFO in time at a higher
+/// rate than the main loop empties the PIO's.
+#[cfg(not(feature = "pio-link"))]
+const LINK_BAUD: u32 = 1_000_000;
+#[cfg(feature = "pio-link")]
+const LINK_BAUD: u32 = 115_200;
+/// How long the acknowledgement and the status of a verified update get to leave the link before
+/// the slave restarts into it.
+const UPDATE_REPORT_MS: u32 = 5;
+/// How fast the LED blinks while the master runs incompatible firmware.
+const INCOMPATIBLE_BLINK_MS: u32 = 100;
+/// While a layer above the base one is active, the LED blinks this many times slower.
+const LAYER_BLINK_PERIOD: u8 = 5;
+const DEBOUNCE: Algorithm = Algorithm::EagerPress { window_us: 5_000 };
+const DIODES: DiodeDirection = DiodeDirection::Row2Col;
+/// Every key on this board has a diode.
+const GHOST_DETECTION: bool = false;
+/// How long the PIO scanner lets the columns settle, in units of 32 PIO cycles.
+#[cfg(feature = "pio-scan")]
+const PIO_SETTLE: u8 = 4;
+
 #[allow(unused)]
-pub fn run() -> ! {
-    let mut pac = Peripherals::take().unwrap();
-    let core = CorePeripherals::take().unwrap();
+pub fn run(mut pac: Peripherals, core: CorePeripherals, hand: Hand) -> ! {
     let mut watchdog = Watchdog::new(pac.WATCHDOG);
 
     let clocks = init_clocks_and_plls(
@@ -49,12 +100,14 @@ pub fn run() -> ! {
 
     let sio = Sio::new(pac.SIO);
 
-    let pins = Pins::new(
+    let mut gpio = hardware::pins_by_number(Pins::new(
         pac.IO_BANK0,
         pac.PADS_BANK0,
         sio.gpio_bank0,
         &mut pac.RESETS,
-    );
+    ));
+    let mut pin = |num: u8| gpio[num as usize].take().unwrap();
+    let half = hand.half();
 
     cfg_if::cfg_if! {
         if #[cfg(debug_assertions)] {
@@ -64,92 +117,235 @@ pub fn run() -> ! {
         }
     }
 
-    const ROWS: usize = 5;
-    const COLS: usize = 6;
-    let mut rows = [
-        DynPin::from(pins.gpio20),
-        DynPin::from(pins.gpio19),
-        DynPin::from(pins.gpio18),
-        DynPin::from(pins.gpio17),
-        DynPin::from(pins.gpio16),
-    ];
-    rows.iter_mut().for_each(|p| p.into_push_pull_output());
-
-    let mut cols = [
-        DynPin::from(pins.gpio21),
-        DynPin::from(pins.gpio22),
-        DynPin::from(pins.gpio10),
-        DynPin::from(pins.gpio11),
-        DynPin::from(pins.gpio7),
-        DynPin::from(pins.gpio9),
-    ];
-    cols.iter_mut().for_each(|p| p.into_pull_down_input());
-
-    let mut butmat = ButtonMatrix { rows, cols };
+    let mut rows = half.rows.map(&mut pin);
+    let mut cols = half.cols.map(&mut pin);
+    cols.iter_mut().for_each(|p| match DIODES {
+        DiodeDirection::Row2Col => p.into_pull_down_input(),
+        DiodeDirection::Col2Row => p.into_pull_up_input(),
+    });
+
+    let mut diagnostics = Diagnostics::new();
+
+    #[cfg(feature = "pio-scan")]
+    let mut butmat = {
+        rows.iter_mut().for_each(|p| {
+            p.try_into_mode(DynPinMode::Function(DynFunction::Pio0))
+                .unwrap()
+        });
+        let (mut pio, sm0, _, _, _) = pac.PIO0.split(&mut pac.RESETS);
+        PioMatrix::new(
+            &mut pio,
+            sm0,
+            rows.map(|p| p.id().num),
+            cols.map(|p| p.id().num),
+            DIODES,
+            PIO_SETTLE,
+        )
+        .unwrap()
+    };
+
+    #[cfg(not(feature = "pio-scan"))]
+    let mut butmat = {
+        rows.iter_mut().for_each(|p| p.into_push_pull_output());
+        let row_bits = core::array::from_fn(|ri| rows[ri].id().num);
+        let mut butmat = PortMatrix {
+            cols: cols.map(|p| p.id().num),
+            rows,
+            bank: SioBank,
+            diodes: DIODES,
+            settle_reads: [SETTLE_FALLBACK; ROWS],
+        };
+        if let Err(err) = butmat.calibrate(row_bits, SETTLE_MARGIN) {
+            butmat.settle_reads = [SETTLE_FALLBACK; ROWS];
+            diagnostics.record_error(err, timer.get_counter().ticks());
+        }
+        butmat
+    };
+    let mut ghost_filter = GhostFilter::new(GHOST_DETECTION);
+    let mut debouncer = Debouncer::new(DEBOUNCE);
 
     // let mut p1 = pins.gpio12.into_push_pull_output();
     // let mut p2 = pins.gpio13.into_push_pull_output();
 
-    let uart_pins = (
-        pins.gpio12
-            .into_mode::<rp_pico::hal::gpio::pin::FunctionUart>(),
-        pins.gpio13
-            .into_mode::<rp_pico::hal::gpio::pin::FunctionUart>(),
-    );
-    let mut uart = rp_pico::hal::uart::UartPeripheral::new(pac.UART0, uart_pins, &mut pac.RESETS)
-        .enable(
-            UartConfig::new(115200.Hz(), DataBits::Eight, None, StopBits::One),
-            clocks.peripheral_clock.freq(),
+    #[cfg(feature = "single-wire")]
+    let mut uart = {
+        let mut wire_pin = pin(half.link.0);
+        wire_pin.into_pull_up_input();
+        wire_pin
+            .try_into_mode(DynPinMode::Function(DynFunction::Pio1))
+            .unwrap();
+        let (mut pio, sm0, sm1, _, _) = pac.PIO1.split(&mut pac.RESETS);
+        let wire = PioWire::new(
+            &mut pio,
+            sm0,
+            sm1,
+            wire_pin.id().num,
+            LINK_BAUD,
+            clocks.system_clock.freq().to_Hz(),
         )
         .unwrap();
+        HalfDuplex::new(wire, &timer, Role::Slave)
+    };
+
+    #[cfg(all(feature = "pio-link", not(feature = "single-wire")))]
+    let mut uart = {
+        let mut tx = pin(half.link.0);
+        tx.try_into_mode(DynPinMode::Function(DynFunction::Pio1))
+            .unwrap();
+        let mut rx = pin(half.link.1);
+        rx.into_pull_up_input();
+        let (mut pio, sm0, sm1, _, _) = pac.PIO1.split(&mut pac.RESETS);
+        PioUart::new(
+            &mut pio,
+            sm0,
+            sm1,
+            tx.id().num,
+            rx.id().num,
+            LINK_BAUD,
+            clocks.system_clock.freq().to_Hz(),
+        )
+        .unwrap()
+    };
+
+    #[cfg(not(feature = "pio-link"))]
+    let mut uart = {
+        let uart_pins = DynUartPins::new(pin(half.link.0), pin(half.link.1)).unwrap();
+        let uart = rp_pico::hal::uart::UartPeripheral::new(pac.UART0, uart_pins, &mut pac.RESETS)
+            .enable(
+                UartConfig::new(LINK_BAUD.Hz(), DataBits::Eight, None, StopBits::One),
+                clocks.peripheral_clock.freq(),
+            )
+            .unwrap();
+        unsafe { IrqUart::new(uart) }
+    };
 
     let mut tick_count_down = timer.count_down();
     tick_count_down.start(500.micros());
 
     let mut scan_count_down = timer.count_down();
-    scan_count_down.start(500.micros());
+    scan_count_down.start(250.micros());
 
-    let mut led_on = false;
-    let mut led_pin = pins.led.into_push_pull_output();
+    let mut led_pin = pin(hardware::LED);
+    led_pin.into_push_pull_output();
     let mut blink_count_down = timer.count_down();
-    blink_count_down.start(500.millis());
-
-    let mut t_last_read = Some(0);
+    blink_count_down.start(INCOMPATIBLE_BLINK_MS.millis());
 
-    let mut prev_pressed: Option<[[bool; 6]; 5]> = None;
+    let mut prev_pressed = [[false; COLS]; ROWS];
+    // The empty state encodes to zeros.
+    let mut state = [0; encoded_len(ROWS, COLS)];
+    let mut link = SlaveLink::new(Hello::new(Peer::Slave, ROWS, COLS));
+    let mut rom_flash = RomFlash::new();
+    let mut download = Download::new(flash::active_len());
+    // Whether this firmware has talked to the master, and so works well enough to be kept.
+    let mut confirmed = false;
+    // The active layer of the master and its Caps Lock, which the LED shows.
+    let mut layer = 0;
+    let mut caps_lock = false;
+    let mut blink_count: u8 = 0;
     loop {
-        // if blink_count_down.wait().is_ok() {
-        //     if let Some(tl) = t_last_read {
-        //         if timer.get_counter().ticks() - tl < 200_000 {
-        //             blink_count_down.start(200.millis());
-        //         } else {
-        //             blink_count_down.start(500.millis());
-        //         }
-        //     }
-        //     if led_on {
-        //         led_pin.set_low().unwrap();
-        //     } else {
-        //         led_pin.set_high().unwrap();
-        //     }
-        //     led_on = !led_on;
-        // }
-
-        if let Ok(_byte) = uart.read() {
-            if let Some(cur_pressed) = prev_pressed {
-                let encoded = encode(&cur_pressed);
-                uart.write_full_blocking(&encoded);
+        if let Some(LinkState::Lost) = link.update_link_state(timer.get_counter().ticks()) {
+            // The state of the host is unknown without the master.
+            layer = 0;
+            caps_lock = false;
+        }
+        match link.poll(&mut uart, timer.get_counter().ticks(), &state) {
+            Some(Message::Layer(l)) => layer = l,
+            Some(Message::HostLeds(leds)) => caps_lock = leds & 0b10 != 0,
+            Some(Message::Config(Setting::DebounceMs(ms))) => {
+                debouncer.set_window(ms as u32 * 1000);
+            }
+            Some(Message::Command(Command::Bootloader)) => {
+                // Give the acknowledgement time to leave the UART.
+                delay.delay_ms(1);
+                reset_to_usb_boot(0, 0);
             }
-            t_last_read = Some(timer.get_counter().ticks());
+            Some(Message::Update(step)) => match download.handle(step, &mut rom_flash) {
+                Ok(true) => {
+                    link.report_update(&mut uart, UpdateStatus::Verified);
+                    delay.delay_ms(UPDATE_REPORT_MS);
+                    SCB::sys_reset();
+                }
+                Ok(false) => {}
+                Err(err) => link.report_update(&mut uart, UpdateStatus::Failed(err)),
+            },
+            None => {}
+        }
+
+        // Any hello from the master shows that this firmware works, even one of another protocol
+        // version, since the master may be the next to be updated.
+        if !confirmed && link.peer().is_some() {
+            flash::confirm();
+            confirmed = true;
         }
 
-        if scan_count_down.wait().is_ok() {
-            if let Some(pressed) = butmat.scan(&mut delay) {
+        // The LED shows Caps Lock, and blinks slowly while a layer above the base one is active.
+        // It blinks fast while the master is there but can't be talked to.
+        if blink_count_down.wait().is_ok() {
+            blink_count = blink_count.wrapping_add(1);
+        }
+        let led_on = if link.peer().is_some() && !link.is_compatible() {
+            blink_count % 2 == 0
+        } else if layer != 0 {
+            blink_count / LAYER_BLINK_PERIOD % 2 == 0
+        } else {
+            caps_lock
+        };
+        led_pin.set_state(PinState::from(led_on)).unwrap();
+
+        // The PIO scans the matrix while the loop goes on, and is only started on time.
+        #[cfg(feature = "pio-scan")]
+        let scanned = {
+            let now = timer.get_counter().ticks();
+            if scan_count_down.wait().is_ok() {
+                butmat.start(now);
+            }
+            butmat.poll(now)
+        };
+        #[cfg(not(feature = "pio-scan"))]
+        let scanned = scan_count_down
+            .wait()
+            .is_ok()
+            .then(|| butmat.scan(&mut delay));
+        match scanned {
+            Some(Ok(raw)) => {
+                let now = timer.get_counter().ticks();
+                let pressed = ghost_filter.filter(&debouncer.update(&raw, now));
+                diagnostics.record(&raw, &pressed, now);
                 if pressed[4][0] && pressed[0][5] && pressed[0][0] {
                     reset_to_usb_boot(0, 0);
                 }
 
-                prev_pressed = Some(pressed);
+                for (ri, (row, prev_row)) in pressed.iter().zip(prev_pressed.iter()).enumerate() {
+                    for (ci, (&key, &prev_key)) in row.iter().zip(prev_row.iter()).enumerate() {
+                        if key != prev_key {
+                            link.push_event(Event {
+                                row: ri as u8,
+                                col: ci as u8,
+                                pressed: key,
+                                time_us: now as u32,
+                            });
+                        }
+                    }
+                }
+                prev_pressed = pressed;
+                encode(&prev_pressed, &mut state).unwrap();
             }
+            Some(Err(err)) => diagnostics.record_error(err, timer.get_counter().ticks()),
+            None => {}
+        }
+
+        if cfg!(debug_assertions)
+            && hardware::serial::available()
+            && hardware::serial::read_byte() == b'd'
+        {
+            hardware::serial::print!(
+                "{}link: {:?}\r\npeer: {:?}\r\ndropped events: {}\r\nlayer: {}\r\n",
+                diagnostics.report(timer.get_counter().ticks()),
+                link.link_state(),
+                link.peer(),
+                link.dropped_events(),
+                layer
+            );
         }
     }
 }
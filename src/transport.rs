@@ -0,0 +1,94 @@
+#[cfg(any(not(feature = "pio-link"), feature = "modules"))]
+use core::marker::PhantomData;
+
+use embedded_hal::serial::Read;
+use rp_pico::hal::uart::{Enabled, UartDevice, UartPeripheral, ValidUartPinout};
+#[cfg(any(not(feature = "pio-link"), feature = "modules"))]
+use rp_pico::{
+    hal::gpio::{DynFunction, DynPin, DynPinMode},
+    pac::{UART0, UART1},
+};
+
+/// A byte stream to the other half.
+pub trait Transport {
+    /// Returns the next received byte, if there is one.
+    fn read(&mut self) -> Option<u8>;
+
+    /// Sends all of `bytes`, blocking until they have been queued for sending.
+    fn write(&mut self, bytes: &[u8]);
+
+    /// Whether a received byte is waiting to be read.
+    fn is_readable(&self) -> bool;
+}
+
+impl<D, P> Transport for UartPeripheral<Enabled, D, P>
+where
+    D: UartDevice,
+    P: ValidUartPinout<D>,
+{
+    fn read(&mut self) -> Option<u8> {
+        // Bytes with framing or parity errors are dropped, and the frame they belong to is
+        // rejected by its CRC.
+        Read::read(self).ok()
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.write_full_blocking(bytes);
+    }
+
+    fn is_readable(&self) -> bool {
+        self.uart_is_readable()
+    }
+}
+
+/// The GPIO numbers of the TX pins of a UART. Each RX pin is the one after its TX pin.
+#[cfg(any(not(feature = "pio-link"), feature = "modules"))]
+pub trait UartTxPins {
+    const TX: [u8; 4];
+}
+
+#[cfg(any(not(feature = "pio-link"), feature = "modules"))]
+impl UartTxPins for UART0 {
+    const TX: [u8; 4] = [0, 12, 16, 28];
+}
+
+#[cfg(any(not(feature = "pio-link"), feature = "modules"))]
+impl UartTxPins for UART1 {
+    const TX: [u8; 4] = [4, 8, 20, 24];
+}
+
+/// UART pins picked at run time, for halves with their links on different pins.
+#[cfg(any(not(feature = "pio-link"), feature = "modules"))]
+pub struct DynUartPins<D> {
+    _tx: DynPin,
+    _rx: DynPin,
+    _uart: PhantomData<D>,
+}
+
+#[cfg(any(not(feature = "pio-link"), feature = "modules"))]
+impl<D: UartTxPins> DynUartPins<D> {
+    /// Switches the pins to the UART function, or returns `None` if they aren't the TX and RX
+    /// pins of the same pinout of the UART.
+    pub fn new(mut tx: DynPin, mut rx: DynPin) -> Option<Self> {
+        if !D::TX.contains(&tx.id().num) || rx.id().num != tx.id().num + 1 {
+            return None;
+        }
+        tx.try_into_mode(DynPinMode::Function(DynFunction::Uart))
+            .ok()?;
+        rx.try_into_mode(DynPinMode::Function(DynFunction::Uart))
+            .ok()?;
+        Some(DynUartPins {
+            _tx: tx,
+            _rx: rx,
+            _uart: PhantomData,
+        })
+    }
+}
+
+#[cfg(any(not(feature = "pio-link"), feature = "modules"))]
+impl<D: UartDevice + UartTxPins> ValidUartPinout<D> for DynUartPins<D> {
+    const TX_ENABLED: bool = true;
+    const RX_ENABLED: bool = true;
+    const CTS_ENABLED: bool = false;
+    const RTS_ENABLED: bool = false;
+}
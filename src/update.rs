@@ -0,0 +1,581 @@
+//! Firmware updates of the slave, sent by the host through the master.
+//!
+//! The host sends `u` to the console of the master, then the length and the CRC-32 of the image
+//! as two little endian words, then the image itself, which is a binary of the whole flash from
+//! the second stage bootloader on. The master forwards it to the slave in [`UpdateStep`]s, which
+//! the slave writes to the update slot of its flash. The slave reports to the master whether the
+//! image matched its CRC once it has all arrived, or which step failed. Then it restarts, and the
+//! slots are swapped at boot.
+//!
+//! The swapped in firmware is on trial: it has to confirm that it works, by talking to the other
+//! half, before it restarts. Otherwise the slots are swapped back at the next boot, and the
+//! previous firmware runs again.
+
+use heapless::Vec;
+
+use crate::protocol::{Message, UpdateStep, CHUNK_LEN};
+
+/// The unit of erasing the flash.
+pub const SECTOR_LEN: u32 = 4096;
+/// The unit of programming the flash.
+pub const PAGE_LEN: usize = 256;
+/// The length of each slot, which is the largest image.
+pub const SLOT_LEN: u32 = 0xFF000;
+/// The offset into the flash of the slot the firmware runs from.
+pub const ACTIVE: u32 = 0;
+/// The offset into the flash of the slot updates are written to.
+pub const UPDATE: u32 = SLOT_LEN;
+/// The offset into the flash of the sector that describes the state of the slots.
+pub const META: u32 = 2 * SLOT_LEN;
+const META_MAGIC: [u8; 4] = *b"KFCU";
+
+/// The flash that holds the slots, addressed by offset from its start.
+pub trait Flash {
+    fn read(&mut self, offset: u32, buf: &mut [u8]);
+
+    /// Erases the sector at `offset`, which is a multiple of [`SECTOR_LEN`].
+    fn erase(&mut self, offset: u32);
+
+    /// Programs the erased page at `offset`, which is a multiple of [`PAGE_LEN`].
+    fn program(&mut self, offset: u32, page: &[u8; PAGE_LEN]);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpdateError {
+    /// The image is empty or doesn't fit in a slot.
+    BadLength,
+    /// A step came out of order, like a chunk that doesn't follow the previous one or a commit
+    /// before the whole image was sent.
+    OutOfOrder,
+    /// The image written to the update slot doesn't match its CRC.
+    Corrupted,
+}
+
+/// CRC-32 (as in zlib) of `data`, continued from the CRC of the data before it, or 0.
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SlotState {
+    /// A verified image waits in the update slot.
+    Pending = 1,
+    /// The slots were swapped, and the new firmware hasn't booted yet.
+    Trial = 2,
+    /// The new firmware booted, and hasn't confirmed that it works yet.
+    Testing = 3,
+    Confirmed = 4,
+    /// The new firmware didn't confirm, so the slots were swapped back.
+    Reverted = 5,
+}
+
+/// The state of the slots, kept in the first page of the [`META`] sector.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Meta {
+    pub state: SlotState,
+    /// The number of sectors swapped between the slots, which covers both images.
+    pub sectors: u32,
+}
+
+impl Meta {
+    pub fn read(flash: &mut impl Flash) -> Option<Meta> {
+        let mut bytes = [0; 9];
+        flash.read(META, &mut bytes);
+        if bytes[..4] != META_MAGIC {
+            return None;
+        }
+        let state = match bytes[4] {
+            1 => SlotState::Pending,
+            2 => SlotState::Trial,
+            3 => SlotState::Testing,
+            4 => SlotState::Confirmed,
+            5 => SlotState::Reverted,
+            _ => return None,
+        };
+        Some(Meta {
+            state,
+            sectors: u32::from_le_bytes(bytes[5..].try_into().unwrap()),
+        })
+    }
+
+    pub fn page(self) -> [u8; PAGE_LEN] {
+        let mut page = [0xFF; PAGE_LEN];
+        page[..4].copy_from_slice(&META_MAGIC);
+        page[4] = self.state as u8;
+        page[5..9].copy_from_slice(&self.sectors.to_le_bytes());
+        page
+    }
+
+    pub fn write(self, flash: &mut impl Flash) {
+        flash.erase(META);
+        flash.program(META, &self.page());
+    }
+}
+
+/// What to do with the slots at boot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Boot {
+    Run,
+    /// Swap the first `sectors` sectors of the slots, then write the meta and restart.
+    Swap(Meta),
+}
+
+/// Decides what to do with the slots at boot, and starts the test of newly swapped in firmware.
+pub fn boot(flash: &mut impl Flash) -> Boot {
+    let Some(meta) = Meta::read(flash) else {
+        return Boot::Run;
+    };
+    match meta.state {
+        SlotState::Pending => Boot::Swap(Meta {
+            state: SlotState::Trial,
+            ..meta
+        }),
+        SlotState::Trial => {
+            Meta {
+                state: SlotState::Testing,
+                ..meta
+            }
+            .write(flash);
+            Boot::Run
+        }
+        // The new firmware restarted before it confirmed that it works.
+        SlotState::Testing => Boot::Swap(Meta {
+            state: SlotState::Reverted,
+            ..meta
+        }),
+        SlotState::Confirmed | SlotState::Reverted => Boot::Run,
+    }
+}
+
+/// Keeps the firmware under test. Returns whether there was one.
+pub fn confirm(flash: &mut impl Flash) -> bool {
+    match Meta::read(flash) {
+        Some(meta) if meta.state == SlotState::Testing => {
+            Meta {
+                state: SlotState::Confirmed,
+                ..meta
+            }
+            .write(flash);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// The slave's side of an update, which writes the image to the update slot.
+pub struct Download {
+    /// The length of the running firmware, which is swapped out with the update.
+    active_len: u32,
+    /// The length and CRC of the image being received.
+    image: Option<(u32, u32)>,
+    written: u32,
+    page: Vec<u8, PAGE_LEN>,
+}
+
+impl Download {
+    pub fn new(active_len: u32) -> Self {
+        Download {
+            active_len,
+            image: None,
+            written: 0,
+            page: Vec::new(),
+        }
+    }
+
+    /// Handles a step of the update. Returns `true` once a verified image waits to be swapped in
+    /// at the next boot.
+    pub fn handle(
+        &mut self,
+        step: UpdateStep,
+        flash: &mut impl Flash,
+    ) -> Result<bool, UpdateError> {
+        match step {
+            UpdateStep::Begin { len, crc } => {
+                self.image = None;
+                if len == 0 || len > SLOT_LEN {
+                    return Err(UpdateError::BadLength);
+                }
+                self.image = Some((len, crc));
+                self.written = 0;
+                self.page.clear();
+                Ok(false)
+            }
+            UpdateStep::Chunk { offset, len, data } => {
+                let Some((image_len, _)) = self.image else {
+                    return Err(UpdateError::OutOfOrder);
+                };
+                if offset != self.written {
+                    return Err(UpdateError::OutOfOrder);
+                }
+                if offset + len as u32 > image_len {
+                    return Err(UpdateError::BadLength);
+                }
+                for &byte in &data[..len as usize] {
+                    if self.written % SECTOR_LEN == 0 {
+                        flash.erase(UPDATE + self.written);
+                    }
+                    // The page is programmed as soon as it's full.
+                    _ = self.page.push(byte);
+                    self.written += 1;
+                    if self.page.is_full() {
+                        self.flush(flash);
+                    }
+                }
+                Ok(false)
+            }
+            UpdateStep::Commit => {
+                let Some((len, crc)) = self.image.take() else {
+                    return Err(UpdateError::OutOfOrder);
+                };
+                if self.written != len {
+                    return Err(UpdateError::OutOfOrder);
+                }
+                self.flush(flash);
+                if slot_crc(flash, UPDATE, len) != crc {
+                    return Err(UpdateError::Corrupted);
+                }
+                let swapped = len.max(self.active_len);
+                Meta {
+                    state: SlotState::Pending,
+                    sectors: (swapped + SECTOR_LEN - 1) / SECTOR_LEN,
+                }
+                .write(flash);
+                Ok(true)
+            }
+        }
+    }
+
+    /// Programs the partial page, padded with erased bytes.
+    fn flush(&mut self, flash: &mut impl Flash) {
+        if self.page.is_empty() {
+            return;
+        }
+        let start = self.written - self.page.len() as u32;
+        let mut page = [0xFF; PAGE_LEN];
+        page[..self.page.len()].copy_from_slice(&self.page);
+        flash.program(UPDATE + start, &page);
+        self.page.clear();
+    }
+}
+
+/// The CRC-32 of the first `len` bytes of the slot at `slot`.
+fn slot_crc(flash: &mut impl Flash, slot: u32, len: u32) -> u32 {
+    let mut crc = 0;
+    let mut buf = [0; PAGE_LEN];
+    let mut offset = 0;
+    while offset < len {
+        let count = (len - offset).min(PAGE_LEN as u32) as usize;
+        flash.read(slot + offset, &mut buf[..count]);
+        crc = crc32(crc, &buf[..count]);
+        offset += count as u32;
+    }
+    crc
+}
+
+/// The master's side of an update, which turns the image sent by the host into messages for the
+/// slave.
+pub struct Upload {
+    /// The length and CRC of the image, as sent by the host.
+    header: Vec<u8, 8>,
+    len: u32,
+    received: u32,
+    chunk: Vec<u8, CHUNK_LEN>,
+    /// The message waiting for room in the outbox.
+    next: Option<Message>,
+    committed: bool,
+}
+
+impl Upload {
+    pub fn new() -> Self {
+        Upload {
+            header: Vec::new(),
+            len: 0,
+            received: 0,
+            chunk: Vec::new(),
+            next: None,
+            committed: false,
+        }
+    }
+
+    /// How many bytes from the host can be taken now, which is none while a message waits.
+    pub fn wanted(&self) -> usize {
+        if self.next.is_some() || self.committed {
+            0
+        } else if !self.header.is_full() {
+            self.header.capacity() - self.header.len()
+        } else {
+            (CHUNK_LEN - self.chunk.len()).min((self.len - self.received) as usize)
+        }
+    }
+
+    /// Takes bytes from the host, at most [`Upload::wanted`] of them.
+    pub fn push(&mut self, bytes: &[u8]) -> Result<(), UpdateError> {
+        let bytes = &bytes[..bytes.len().min(self.wanted())];
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        if !self.header.is_full() {
+            _ = self.header.extend_from_slice(bytes);
+            if self.header.is_full() {
+                let len = u32::from_le_bytes(self.header[..4].try_into().unwrap());
+                let crc = u32::from_le_bytes(self.header[4..].try_into().unwrap());
+                if len == 0 || len > SLOT_LEN {
+                    return Err(UpdateError::BadLength);
+                }
+                self.len = len;
+                self.next = Some(Message::Update(UpdateStep::Begin { len, crc }));
+            }
+            return Ok(());
+        }
+
+        _ = self.chunk.extend_from_slice(bytes);
+        self.received += bytes.len() as u32;
+        if self.chunk.is_full() || self.received == self.len {
+            let mut data = [0; CHUNK_LEN];
+            data[..self.chunk.len()].copy_from_slice(&self.chunk);
+            self.next = Some(Message::Update(UpdateStep::Chunk {
+                offset: self.received - self.chunk.len() as u32,
+                len: self.chunk.len() as u8,
+                data,
+            }));
+            self.chunk.clear();
+        }
+        Ok(())
+    }
+
+    /// Hands the waiting messages to `send`, which returns `false` when it has no room for them.
+    /// Returns `true` once the whole image has been handed over.
+    pub fn flush(&mut self, mut send: impl FnMut(Message) -> bool) -> bool {
+        loop {
+            if self.next.is_none()
+                && !self.committed
+                && self.header.is_full()
+                && self.received == self.len
+            {
+                self.next = Some(Message::Update(UpdateStep::Commit));
+            }
+            let Some(message) = self.next else {
+                return self.committed;
+            };
+            if !send(message) {
+                return false;
+            }
+            self.next = None;
+            if message == Message::Update(UpdateStep::Commit) {
+                self.committed = true;
+            }
+        }
+    }
+
+    /// The bytes of the image received so far, and the length of the image.
+    pub fn progress(&self) -> (u32, u32) {
+        (self.received, self.len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockFlash;
+
+    /// A pseudo-random image of `len` bytes, and the header the host sends before it.
+    fn image(len: u32) -> (std::vec::Vec<u8>, [u8; 8]) {
+        let image: std::vec::Vec<u8> = (0..len).map(|i| (i * 7 + i / 251) as u8).collect();
+        let mut header = [0; 8];
+        header[..4].copy_from_slice(&len.to_le_bytes());
+        header[4..].copy_from_slice(&crc32(0, &image).to_le_bytes());
+        (image, header)
+    }
+
+    /// Sends the host's bytes through an upload, a few at a time, and returns the messages.
+    fn upload(bytes: &[u8]) -> std::vec::Vec<Message> {
+        let mut upload = Upload::new();
+        let mut messages = std::vec::Vec::new();
+        let mut bytes = bytes;
+        loop {
+            let done = upload.flush(|message| {
+                messages.push(message);
+                true
+            });
+            if done {
+                break;
+            }
+            let count = upload.wanted().min(bytes.len()).min(13);
+            upload.push(&bytes[..count]).unwrap();
+            bytes = &bytes[count..];
+        }
+        messages
+    }
+
+    /// Swaps the first `sectors` sectors of the slots, as done at boot.
+    fn swap(flash: &mut MockFlash, meta: Meta) {
+        for sector in 0..meta.sectors {
+            let offset = sector * SECTOR_LEN;
+            let (mut active, mut update) = ([0; SECTOR_LEN as usize], [0; SECTOR_LEN as usize]);
+            flash.read(ACTIVE + offset, &mut active);
+            flash.read(UPDATE + offset, &mut update);
+            flash.erase(ACTIVE + offset);
+            flash.erase(UPDATE + offset);
+            for (page, at) in (0..SECTOR_LEN).step_by(PAGE_LEN).enumerate() {
+                let range = page * PAGE_LEN..(page + 1) * PAGE_LEN;
+                flash.program(
+                    ACTIVE + offset + at,
+                    &update[range.clone()].try_into().unwrap(),
+                );
+                flash.program(UPDATE + offset + at, &active[range].try_into().unwrap());
+            }
+        }
+        meta.write(flash);
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(0, b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn image_reaches_the_update_slot() {
+        let (image, header) = image(3 * SECTOR_LEN + 100);
+        let mut bytes = header.to_vec();
+        bytes.extend_from_slice(&image);
+        let messages = upload(&bytes);
+        assert_eq!(messages.last(), Some(&Message::Update(UpdateStep::Commit)));
+
+        let mut flash = MockFlash::new();
+        let mut download = Download::new(SECTOR_LEN);
+        let mut restart = false;
+        for message in messages {
+            let Message::Update(step) = message else {
+                panic!("not an update: {:?}", message);
+            };
+            restart = download.handle(step, &mut flash).unwrap();
+        }
+        assert!(restart);
+        let mut written = std::vec![0; image.len()];
+        flash.read(UPDATE, &mut written);
+        assert_eq!(written, image);
+        assert_eq!(
+            Meta::read(&mut flash),
+            Some(Meta {
+                state: SlotState::Pending,
+                sectors: 4
+            })
+        );
+    }
+
+    #[test]
+    fn corrupted_images_are_refused() {
+        let (image, mut header) = image(1000);
+        header[4] ^= 1;
+        let mut bytes = header.to_vec();
+        bytes.extend_from_slice(&image);
+
+        let mut flash = MockFlash::new();
+        let mut download = Download::new(SECTOR_LEN);
+        let mut results = upload(&bytes)
+            .into_iter()
+            .filter_map(|message| match message {
+                Message::Update(step) => Some(download.handle(step, &mut flash)),
+                _ => None,
+            });
+        assert!(results.all(|result| result == Ok(false) || result == Err(UpdateError::Corrupted)));
+        assert_eq!(Meta::read(&mut flash), None);
+    }
+
+    #[test]
+    fn steps_out_of_order_are_refused() {
+        let mut flash = MockFlash::new();
+        let mut download = Download::new(0);
+        let chunk = |offset| UpdateStep::Chunk {
+            offset,
+            len: 4,
+            data: [0; CHUNK_LEN],
+        };
+        assert_eq!(
+            download.handle(chunk(0), &mut flash),
+            Err(UpdateError::OutOfOrder)
+        );
+        let begin = UpdateStep::Begin { len: 8, crc: 0 };
+        assert_eq!(download.handle(begin, &mut flash), Ok(false));
+        assert_eq!(
+            download.handle(chunk(4), &mut flash),
+            Err(UpdateError::OutOfOrder)
+        );
+        assert_eq!(download.handle(chunk(0), &mut flash), Ok(false));
+        let commit = UpdateStep::Commit;
+        assert_eq!(
+            download.handle(commit, &mut flash),
+            Err(UpdateError::OutOfOrder)
+        );
+
+        let too_long = UpdateStep::Begin {
+            len: SLOT_LEN + 1,
+            crc: 0,
+        };
+        assert_eq!(
+            download.handle(too_long, &mut flash),
+            Err(UpdateError::BadLength)
+        );
+        assert_eq!(Upload::new().push(&[0; 8]), Err(UpdateError::BadLength));
+    }
+
+    #[test]
+    fn unconfirmed_firmware_is_reverted() {
+        let mut flash = MockFlash::new();
+        flash.erase(ACTIVE);
+        flash.program(ACTIVE, &[1; PAGE_LEN]);
+        flash.erase(UPDATE);
+        flash.program(UPDATE, &[2; PAGE_LEN]);
+        Meta {
+            state: SlotState::Pending,
+            sectors: 1,
+        }
+        .write(&mut flash);
+
+        let Boot::Swap(meta) = boot(&mut flash) else {
+            panic!("the update wasn't swapped in");
+        };
+        swap(&mut flash, meta);
+        assert_eq!(boot(&mut flash), Boot::Run);
+        let mut byte = [0];
+        flash.read(ACTIVE, &mut byte);
+        assert_eq!(byte, [2]);
+
+        // The new firmware restarts without confirming.
+        let Boot::Swap(meta) = boot(&mut flash) else {
+            panic!("the update wasn't reverted");
+        };
+        swap(&mut flash, meta);
+        assert_eq!(boot(&mut flash), Boot::Run);
+        flash.read(ACTIVE, &mut byte);
+        assert_eq!(byte, [1]);
+        assert!(!confirm(&mut flash));
+    }
+
+    #[test]
+    fn confirmed_firmware_is_kept() {
+        let mut flash = MockFlash::new();
+        Meta {
+            state: SlotState::Trial,
+            sectors: 1,
+        }
+        .write(&mut flash);
+        assert_eq!(boot(&mut flash), Boot::Run);
+        assert!(confirm(&mut flash));
+        assert_eq!(boot(&mut flash), Boot::Run);
+        assert_eq!(Meta::read(&mut flash).unwrap().state, SlotState::Confirmed);
+    }
+}